@@ -1,22 +1,50 @@
 mod block_timestamp;
+mod checkpoint;
+mod discovery;
+mod liquidity;
+mod pool;
+mod quarantine;
+mod rpc_fixture;
+mod rpc_pool;
+mod skipped;
 mod swap;
+#[cfg(test)]
+mod test_helper;
+mod trace_backend;
+mod trace_cache;
+mod trace_log;
+mod trace_window;
 
 use crate::download::block_timestamp::{BlockTimestampFetcher, TryIntoBlockTimestamp};
-use crate::download::swap::SwapFetcher;
-use alloy::primitives::BlockNumber;
+use crate::download::checkpoint::Checkpoint;
+use crate::download::discovery::{Discovery, PoolTraceDiscovery};
+use crate::download::liquidity::LiquidityCsv;
+use crate::download::pool::PoolConfig;
+use crate::download::quarantine::FailureCsv;
+use crate::download::rpc_fixture::{RpcRecordLayer, RpcReplay};
+use crate::download::rpc_pool::{RetryOptions, RpcEndpoint, RpcPool};
+use crate::download::swap::{SwapCsv, SwapFetcher};
+use crate::download::trace_backend::TraceBackend;
+use crate::download::trace_cache::TraceCache;
+use crate::download::trace_log::missing_rows;
+use crate::download::trace_window::TraceWindow;
+use crate::helper::{StringifyArrayUsize, parse_timestamp, read_csv};
+use crate::paths::DataPaths;
+use alloy::primitives::{B256, BlockNumber, TxHash};
 use alloy::providers::fillers::{
     BlobGasFiller, ChainIdFiller, FillProvider, GasFiller, JoinFill, NonceFiller,
 };
 use alloy::providers::{Identity, Provider, ProviderBuilder, RootProvider};
 use alloy::rpc::client::RpcClient;
-use alloy::transports::layers::RetryBackoffLayer;
-use eyre::Result;
-use log::info;
+use clap::builder::RangedU64ValueParser;
+use eyre::{Result, bail};
+use futures::{StreamExt, TryStreamExt, stream};
+use log::{debug, info, warn};
+use std::collections::{BTreeSet, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
 
-const MAX_RETRY: u32 = 10;
-const BACKOFF: u64 = 1000;
-const CUPS: u64 = 10_000;
-const STEP: usize = 5_000;
+const DEFAULT_START_BLOCK: BlockNumber = 30_274_134;
 
 pub type ProviderFiller = FillProvider<
     JoinFill<
@@ -26,43 +54,800 @@ pub type ProviderFiller = FillProvider<
     RootProvider,
 >;
 
+#[derive(clap::Args, Debug)]
+pub struct RpcArgs {
+    /// GnosisChain RPC url (http(s)://, ws(s):// or an IPC socket path), repeat it to fail over
+    /// between several endpoints. Append
+    /// `;cups=<N>` for the endpoint compute units budget and `;methods=<prefix>,...` to only route
+    /// these methods to it (e.g. `;methods=trace_,debug_`)
+    #[arg(short, long, required_unless_present = "rpc_replay", value_parser = RpcEndpoint::parse)]
+    pub rpc_url: Vec<RpcEndpoint>,
+
+    /// Retries of a failed request on one endpoint before failing over
+    #[arg(long, default_value = "10")]
+    pub max_retry: u32,
+
+    /// Initial backoff between two retries, in milliseconds
+    #[arg(long, default_value = "1000")]
+    pub backoff: u64,
+
+    /// Compute units per second budget of the endpoints without `;cups=<N>`
+    #[arg(long, default_value = "10000")]
+    pub cups: u64,
+
+    /// Save every JSON-RPC response into this directory, to be replayed with --rpc-replay
+    #[arg(long, conflicts_with = "rpc_replay")]
+    pub rpc_record: Option<PathBuf>,
+
+    /// Answer every JSON-RPC request from the responses recorded in this directory, offline
+    #[arg(long, conflicts_with = "rpc_url")]
+    pub rpc_replay: Option<PathBuf>,
+
+    /// Rpc method used to replay the transactions opcode by opcode
+    #[arg(long, value_enum, default_value_t)]
+    pub trace_backend: TraceBackend,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DownloadArgs {
+    #[command(flatten)]
+    pub rpc: RpcArgs,
+
+    /// The starting block for downloading, default to the block after the checkpoint or to 30274134
+    #[arg(short, long)]
+    pub start_block_download: Option<BlockNumber>,
+
+    /// The last block (inclusive) for downloading, default to the latest block
+    #[arg(short, long)]
+    pub end_block: Option<BlockNumber>,
+
+    /// Start downloading from the first block at or after this date (RFC3339 or YYYY-MM-DD)
+    #[arg(long, value_parser = parse_timestamp, conflicts_with = "start_block_download")]
+    pub from: Option<u64>,
+
+    /// Stop downloading at the last block at or before this date (RFC3339 or YYYY-MM-DD)
+    #[arg(long, value_parser = parse_timestamp, conflicts_with = "end_block")]
+    pub to: Option<u64>,
+
+    /// Pool profile (JSON), default to the sDAI/EURe pool
+    #[arg(short, long)]
+    pub pool_config: Option<PathBuf>,
+
+    /// How the pool calls are found in a block range
+    #[arg(long, value_enum, default_value_t)]
+    pub discovery: Discovery,
+
+    /// Maximum number of block ranges, and of traces inside a range, fetched concurrently
+    #[arg(short, long, default_value = "4", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub concurrency: usize,
+
+    /// Keep downloading the new blocks once the latest block is reached
+    #[arg(short, long, conflicts_with_all = ["end_block", "to"])]
+    pub follow: bool,
+
+    /// Following mode: only download the blocks with at least this number of confirmations
+    #[arg(long, default_value = "10")]
+    pub confirmations: u64,
+
+    /// Following mode: seconds between two polls of the latest block
+    #[arg(long, default_value = "30", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    pub poll_interval: u64,
+
+    /// Number of already scanned blocks checked against the canonical chain before resuming
+    #[arg(long, default_value = "64", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    pub reorg_depth: u64,
+
+    /// Record the traces failing to decode in failures.csv and continue, instead of stopping the
+    /// download. Decode them again with the retry command.
+    #[arg(long)]
+    pub quarantine: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct InspectArgs {
+    #[command(flatten)]
+    pub rpc: RpcArgs,
+
+    /// The transaction to inspect
+    pub tx_hash: TxHash,
+
+    /// Pool profile (JSON), default to the sDAI/EURe pool
+    #[arg(short, long)]
+    pub pool_config: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RebuildArgs {
+    /// Pool profile (JSON), default to the sDAI/EURe pool
+    #[arg(short, long)]
+    pub pool_config: Option<PathBuf>,
+
+    /// Replace swaps.csv and liquidity.csv even when some of their rows are not rebuilt, e.g. their
+    /// transactions are missing from the trace cache
+    #[arg(long)]
+    pub allow_missing: bool,
+
+    /// Maximum number of cached traces read concurrently
+    #[arg(short, long, default_value = "4", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub concurrency: usize,
+
+    /// Backend the vm traces were cached with
+    #[arg(long, value_enum, default_value_t)]
+    pub trace_backend: TraceBackend,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RetryArgs {
+    /// Pool profile (JSON), default to the sDAI/EURe pool
+    #[arg(short, long)]
+    pub pool_config: Option<PathBuf>,
+
+    /// Maximum number of cached traces read concurrently
+    #[arg(short, long, default_value = "4", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub concurrency: usize,
+
+    /// Backend the vm traces were cached with
+    #[arg(long, value_enum, default_value_t)]
+    pub trace_backend: TraceBackend,
+}
+
 // TODO Add spot price for EUR/USD, maybe add price_rate infos
-pub async fn start(rpc_url: &str, start_block_download: BlockNumber) -> Result<()> {
+pub async fn start(args: &DownloadArgs, data_paths: &DataPaths) -> Result<()> {
     info!("Downloading data from rpc...");
 
-    let client = RpcClient::builder()
-        .layer(RetryBackoffLayer::new(MAX_RETRY, BACKOFF, CUPS))
-        .http(rpc_url.parse()?);
-    let provider = ProviderBuilder::new().connect_client(client);
+    let provider = connect(&args.rpc).await?;
 
-    let block_timestamp_fetcher = BlockTimestampFetcher::try_new(provider.clone())?;
-    let mut swap_fetcher = SwapFetcher::try_new(provider.clone(), block_timestamp_fetcher)?;
+    let block_timestamp_fetcher =
+        BlockTimestampFetcher::try_new(Some(provider.clone()), data_paths)?;
+    let trace_cache = TraceCache::try_new(
+        Some(provider.clone()),
+        args.rpc.trace_backend,
+        data_paths.trace_cache_dir(),
+    )?;
+    let pool = PoolConfig::load(args.pool_config.as_deref())?;
+    let discovery =
+        PoolTraceDiscovery::try_new(&provider, args.discovery, pool.clone(), args.concurrency)
+            .await?;
+    let mut swap_fetcher = SwapFetcher::try_new(
+        trace_cache,
+        pool,
+        block_timestamp_fetcher,
+        data_paths,
+        args.concurrency,
+    )?;
+    swap_fetcher.quarantine = args.quarantine;
 
-    let latest_block = provider.get_block_number().await?;
+    let checkpoint_file = data_paths.checkpoint();
+    let checkpoint = Checkpoint::load(&checkpoint_file)?;
+    let mut start_block = match args.from {
+        Some(from) => swap_fetcher
+            .block_timestamp_fetcher
+            .block_at_or_before(from.saturating_sub(1))
+            .await?
+            .saturating_add(1),
+        None => match (args.start_block_download, &checkpoint) {
+            (Some(start_block_download), _) => start_block_download,
+            (None, Some(checkpoint)) => {
+                info!("Resuming after block {}", checkpoint.to_block);
+                checkpoint.to_block.saturating_add(1)
+            }
+            (None, None) => DEFAULT_START_BLOCK,
+        },
+    };
+    let end_block = match (args.end_block, args.to) {
+        (Some(end_block), _) => end_block,
+        (None, Some(to)) => {
+            swap_fetcher
+                .block_timestamp_fetcher
+                .block_at_or_before(to)
+                .await?
+        }
+        (None, None) => provider
+            .get_block_number()
+            .await?
+            .saturating_sub(if args.follow { args.confirmations } else { 0 }),
+    };
+    let is_resuming = args.from.is_none() && args.start_block_download.is_none();
+    if start_block > end_block && !is_resuming {
+        bail!(
+            "The start block {} is after the end block {}",
+            start_block,
+            end_block
+        );
+    }
 
-    for current_block in (start_block_download..=latest_block).step_by(STEP) {
-        let current_block_timestamp = current_block
-            .try_into_block_timestamp(&mut swap_fetcher.block_timestamp_fetcher)
-            .await?;
+    let mut block_scanner = BlockScanner {
+        provider: provider.clone(),
+        swap_fetcher,
+        discovery,
+        trace_window: TraceWindow::default(),
+        concurrency: args.concurrency,
+        checkpoint_file,
+        checkpoint,
+        reorg_depth: args.reorg_depth,
+    };
+
+    if is_resuming
+        && start_block > 0
+        && let Some(rollback_block) = block_scanner.reconcile(start_block - 1).await?
+    {
+        start_block = rollback_block;
+    }
+
+    if start_block <= end_block {
+        info!("Downloading blocks {}..={}", start_block, end_block);
+        block_scanner.scan(start_block, end_block).await?;
+    } else {
+        info!("Already scanned up to block {}", end_block);
+    }
+
+    if args.follow {
+        let mut next_block = start_block.max(end_block.saturating_add(1));
         info!(
-            "Downloading block {}/{} ({})",
-            current_block,
-            latest_block,
-            chrono::DateTime::<chrono::Utc>::from_timestamp(current_block_timestamp as i64, 0)
-                .unwrap()
-                .to_rfc3339()
+            "Following new blocks with {} confirmations...",
+            args.confirmations
         );
 
-        swap_fetcher
-            .fetch_swap_csv(
-                current_block,
-                current_block.saturating_add(STEP.saturating_sub(1) as u64),
-            )
-            .await?;
+        loop {
+            tokio::time::sleep(Duration::from_secs(args.poll_interval)).await;
 
-        swap_fetcher.flush()?
+            if let Err(error) = block_scanner
+                .follow(&mut next_block, args.confirmations)
+                .await
+            {
+                warn!(
+                    "Failed to follow from block {}, retrying at the next poll: {:?}",
+                    next_block, error
+                );
+            }
+        }
     }
 
     info!("Downloading data from rpc done.");
     Ok(())
 }
+
+/// Scan block ranges in order, the checkpoint is saved after every flushed range
+struct BlockScanner {
+    provider: ProviderFiller,
+    swap_fetcher: SwapFetcher,
+    discovery: PoolTraceDiscovery,
+    trace_window: TraceWindow,
+    concurrency: usize,
+    checkpoint_file: PathBuf,
+    checkpoint: Option<Checkpoint>,
+    reorg_depth: u64,
+}
+
+/// Last block of `block_hashes`, the stored hashes of the last `reorg_depth` scanned blocks, still
+/// in the canonical chain given by `canonical_hash`, None when the tip is canonical. Fail when even
+/// the first block is reorged.
+async fn find_last_canonical_block(
+    block_hashes: &[(BlockNumber, B256)],
+    reorg_depth: u64,
+    canonical_hash: impl AsyncFn(BlockNumber) -> Result<B256>,
+) -> Result<Option<BlockNumber>> {
+    // A block hash commits to all its ancestors, the tip is enough when it still matches and
+    // the stored hashes are canonical up to a block then reorged after it
+    let Some(&(tip_block, tip_hash)) = block_hashes.last() else {
+        return Ok(None);
+    };
+    if canonical_hash(tip_block).await? == tip_hash {
+        return Ok(None);
+    }
+
+    let (first_block, first_hash) = block_hashes[0];
+    if canonical_hash(first_block).await? != first_hash {
+        bail!(
+            "Reorg deeper than the {} checked blocks, block {} is already reorged. Restart with a \
+             larger --reorg-depth to find the last canonical block",
+            reorg_depth,
+            first_block
+        );
+    }
+
+    // block_hashes[canonical_index] is canonical, block_hashes[reorged_index] is reorged
+    let (mut canonical_index, mut reorged_index) = (0, block_hashes.len() - 1);
+    while reorged_index - canonical_index > 1 {
+        let middle_index = canonical_index + (reorged_index - canonical_index) / 2;
+        let (block_number, block_hash) = block_hashes[middle_index];
+        if canonical_hash(block_number).await? == block_hash {
+            canonical_index = middle_index;
+        } else {
+            reorged_index = middle_index;
+        }
+    }
+    let last_kept_block = block_hashes[canonical_index].0;
+    warn!(
+        "Reorg detected after block {} (block {} is reorged), rolling back to block {}",
+        last_kept_block, block_hashes[reorged_index].0, last_kept_block
+    );
+
+    Ok(Some(last_kept_block))
+}
+
+impl BlockScanner {
+    /// Compare the stored hashes of the last `reorg_depth` scanned blocks with the canonical
+    /// chain, on a mismatch everything after the last still canonical stored block is rolled
+    /// back and the block after it is returned as the next block to scan. Fail when even the
+    /// first checked block is reorged, the reorg is deeper than `reorg_depth`.
+    async fn reconcile(&mut self, last_scanned_block: BlockNumber) -> Result<Option<BlockNumber>> {
+        let first_checked_block = last_scanned_block.saturating_sub(self.reorg_depth - 1);
+        let block_hashes = self
+            .swap_fetcher
+            .block_timestamp_fetcher
+            .block_hashes(first_checked_block..=last_scanned_block);
+
+        let block_timestamp_fetcher = &self.swap_fetcher.block_timestamp_fetcher;
+        let Some(last_kept_block) =
+            find_last_canonical_block(&block_hashes, self.reorg_depth, async |block_number| {
+                block_timestamp_fetcher
+                    .fetch_canonical_hash(block_number)
+                    .await
+            })
+            .await?
+        else {
+            return Ok(None);
+        };
+        let rollback_block = last_kept_block + 1;
+
+        self.swap_fetcher.rollback(rollback_block)?;
+        let from_block = self
+            .checkpoint
+            .as_ref()
+            .map(|checkpoint| checkpoint.from_block)
+            .unwrap_or(last_kept_block)
+            .min(last_kept_block);
+        let checkpoint = Checkpoint {
+            from_block,
+            to_block: last_kept_block,
+        };
+        checkpoint.save(&self.checkpoint_file)?;
+        self.checkpoint = Some(checkpoint);
+
+        Ok(Some(rollback_block))
+    }
+
+    /// Reconcile then scan the blocks confirmed since `next_block`, which is moved to the next
+    /// block to scan as soon as a step succeeds
+    async fn follow(&mut self, next_block: &mut BlockNumber, confirmations: u64) -> Result<()> {
+        if *next_block > 0
+            && let Some(rollback_block) = self.reconcile(*next_block - 1).await?
+        {
+            *next_block = rollback_block;
+        }
+        let confirmed_block = self
+            .provider
+            .get_block_number()
+            .await?
+            .saturating_sub(confirmations);
+        if confirmed_block < *next_block {
+            debug!(
+                "No new confirmed block after block {}",
+                next_block.saturating_sub(1)
+            );
+            return Ok(());
+        }
+
+        self.scan(*next_block, confirmed_block).await?;
+        *next_block = confirmed_block + 1;
+        Ok(())
+    }
+
+    async fn scan(&mut self, start_block: BlockNumber, end_block: BlockNumber) -> Result<()> {
+        let pool_address = self.swap_fetcher.pool.pool_address;
+        let mut next_block = start_block;
+        let mut has_warned_gap = false;
+
+        while next_block <= end_block {
+            let ranges = self
+                .trace_window
+                .ranges(next_block, end_block, self.concurrency);
+            let pool_traces_vec: Vec<_> = stream::iter(ranges.iter())
+                .map(|&(from_block, to_block)| {
+                    self.discovery.discover(
+                        &self.provider,
+                        &self.swap_fetcher.trace_cache,
+                        from_block,
+                        to_block,
+                    )
+                })
+                .buffered(self.concurrency)
+                .try_collect()
+                .await?;
+
+            let has_split = pool_traces_vec.iter().any(|(_, has_split)| *has_split);
+            let max_traces = pool_traces_vec
+                .iter()
+                .map(|(localized_traces, _)| localized_traces.len())
+                .max()
+                .unwrap_or_default();
+
+            for (&(from_block, to_block), (localized_traces, _)) in
+                ranges.iter().zip(pool_traces_vec)
+            {
+                self.swap_fetcher
+                    .block_timestamp_fetcher
+                    .prefetch_timestamps(
+                        localized_traces
+                            .iter()
+                            .filter_map(|localized_trace| localized_trace.block_number)
+                            .chain([from_block, to_block]),
+                    )
+                    .await?;
+                let current_block_timestamp = from_block
+                    .try_into_block_timestamp(&mut self.swap_fetcher.block_timestamp_fetcher)
+                    .await?;
+                info!(
+                    "Downloading block {}/{} ({})",
+                    from_block,
+                    end_block,
+                    chrono::DateTime::<chrono::Utc>::from_timestamp(
+                        current_block_timestamp as i64,
+                        0
+                    )
+                    .unwrap()
+                    .to_rfc3339()
+                );
+
+                self.swap_fetcher
+                    .trace_cache
+                    .store_pool_traces(pool_address, &localized_traces)?;
+                self.swap_fetcher.process_traces(localized_traces).await?;
+                // Keep the hash of the range tip, reconcile needs it to detect reorgs
+                to_block
+                    .try_into_block_timestamp(&mut self.swap_fetcher.block_timestamp_fetcher)
+                    .await?;
+
+                self.swap_fetcher.flush()?;
+                let scanned = Checkpoint {
+                    from_block: start_block,
+                    to_block,
+                };
+                match &self.checkpoint {
+                    None => self.save_checkpoint(scanned)?,
+                    Some(checkpoint) => match checkpoint.merge(&scanned) {
+                        Some(checkpoint) => self.save_checkpoint(checkpoint)?,
+                        None if !has_warned_gap => {
+                            warn!(
+                                "Blocks {}..={} are not contiguous with the checkpoint {}..={}, \
+                                 the checkpoint is kept and a resume starts after block {}",
+                                start_block,
+                                end_block,
+                                checkpoint.from_block,
+                                checkpoint.to_block,
+                                checkpoint.to_block
+                            );
+                            has_warned_gap = true;
+                        }
+                        None => {}
+                    },
+                }
+                next_block = to_block + 1;
+            }
+
+            self.trace_window.adapt(has_split, max_traces);
+        }
+
+        Ok(())
+    }
+
+    fn save_checkpoint(&mut self, checkpoint: Checkpoint) -> Result<()> {
+        checkpoint.save(&self.checkpoint_file)?;
+        self.checkpoint = Some(checkpoint);
+        Ok(())
+    }
+}
+
+pub async fn inspect(args: &InspectArgs, data_paths: &DataPaths) -> Result<()> {
+    info!("Inspecting transaction {}...", args.tx_hash);
+
+    let provider = connect(&args.rpc).await?;
+
+    let block_timestamp_fetcher =
+        BlockTimestampFetcher::try_new(Some(provider.clone()), data_paths)?;
+    let trace_cache = TraceCache::try_new(
+        Some(provider),
+        args.rpc.trace_backend,
+        data_paths.trace_cache_dir(),
+    )?;
+    let pool = PoolConfig::load(args.pool_config.as_deref())?;
+    let mut swap_fetcher =
+        SwapFetcher::try_new(trace_cache, pool, block_timestamp_fetcher, data_paths, 1)?;
+
+    let decoded_traces = swap_fetcher.inspect_transaction(args.tx_hash).await?;
+    swap_fetcher.block_timestamp_fetcher.flush()?;
+
+    for skipped_csv in decoded_traces.skipped_csv_vec {
+        info!(
+            "Skipped pool interaction {} ({:?})",
+            skipped_csv.trace_path, skipped_csv.reason
+        );
+    }
+
+    if decoded_traces.swap_csv_vec.is_empty() {
+        info!("No swap found in transaction {}", args.tx_hash);
+    }
+    for swap_csv in decoded_traces.swap_csv_vec {
+        println!("{}", serde_json::to_string_pretty(&swap_csv)?);
+    }
+    for liquidity_csv in decoded_traces.liquidity_csv_vec {
+        println!("{}", serde_json::to_string_pretty(&liquidity_csv)?);
+    }
+
+    Ok(())
+}
+
+/// Decode again every cached pool trace into fresh swaps.csv, liquidity.csv, skipped.csv and
+/// failures.csv without any rpc call, written next to them with a `.rebuild` extension. They only
+/// replace the current files when every row of swaps.csv and liquidity.csv was rebuilt, a
+/// transaction missing from the trace cache must not silently delete its swaps.
+pub async fn rebuild(args: &RebuildArgs, data_paths: &DataPaths) -> Result<()> {
+    info!("Rebuilding swaps from the trace cache...");
+
+    let rebuild_data_paths = DataPaths {
+        swaps_csv: Some(data_paths.swaps_csv().with_extension("csv.rebuild")),
+        liquidity_csv: Some(data_paths.liquidity_csv().with_extension("csv.rebuild")),
+        skipped_csv: Some(data_paths.skipped_csv().with_extension("csv.rebuild")),
+        failures_csv: Some(data_paths.failures_csv().with_extension("csv.rebuild")),
+        ..data_paths.clone()
+    };
+    let rebuilt_files = [
+        (rebuild_data_paths.swaps_csv(), data_paths.swaps_csv()),
+        (
+            rebuild_data_paths.liquidity_csv(),
+            data_paths.liquidity_csv(),
+        ),
+        (rebuild_data_paths.skipped_csv(), data_paths.skipped_csv()),
+        (rebuild_data_paths.failures_csv(), data_paths.failures_csv()),
+    ];
+    for (rebuild_file, _) in rebuilt_files.iter() {
+        if rebuild_file.exists() {
+            std::fs::remove_file(rebuild_file)?;
+        }
+    }
+
+    let block_timestamp_fetcher = BlockTimestampFetcher::try_new(None, data_paths)?;
+    let trace_cache = TraceCache::try_new(None, args.trace_backend, data_paths.trace_cache_dir())?;
+    let pool = PoolConfig::load(args.pool_config.as_deref())?;
+    let localized_traces = trace_cache.load_pool_traces(pool.pool_address)?;
+    let mut swap_fetcher = SwapFetcher::try_new(
+        trace_cache,
+        pool,
+        block_timestamp_fetcher,
+        &rebuild_data_paths,
+        args.concurrency,
+    )?;
+    // A trace failing to decode ends in failures.csv.rebuild instead of stopping the rebuild
+    swap_fetcher.quarantine = true;
+
+    let swap_csv_vec = swap_fetcher.process_traces(localized_traces).await?;
+    swap_fetcher.flush()?;
+
+    let missing_swap_csv_vec =
+        missing_rows::<SwapCsv>(&data_paths.swaps_csv(), &rebuild_data_paths.swaps_csv())?;
+    let missing_liquidity_csv_vec = missing_rows::<LiquidityCsv>(
+        &data_paths.liquidity_csv(),
+        &rebuild_data_paths.liquidity_csv(),
+    )?;
+    let missing_rows: Vec<String> = missing_swap_csv_vec
+        .iter()
+        .map(|swap_csv| {
+            format!(
+                "swap {} {} {}",
+                swap_csv.block_number, swap_csv.tx_hash, swap_csv.trace_path
+            )
+        })
+        .chain(missing_liquidity_csv_vec.iter().map(|liquidity_csv| {
+            format!(
+                "liquidity {} {} {}",
+                liquidity_csv.block_number, liquidity_csv.tx_hash, liquidity_csv.trace_path
+            )
+        }))
+        .collect();
+    if !missing_rows.is_empty() {
+        if !args.allow_missing {
+            bail!(
+                "{} rows would be deleted by the rebuild, the current files are kept and the \
+                 rebuilt ones left in *.csv.rebuild (--allow-missing to replace anyway):\n{}",
+                missing_rows.len(),
+                missing_rows.join("\n")
+            );
+        }
+        warn!(
+            "Deleting {} rows missing from the rebuild:\n{}",
+            missing_rows.len(),
+            missing_rows.join("\n")
+        );
+    }
+
+    for (rebuild_file, file) in rebuilt_files.iter() {
+        std::fs::rename(rebuild_file, file)?;
+    }
+
+    info!(
+        "Rebuilding swaps from the trace cache done.({} swaps)",
+        swap_csv_vec.len()
+    );
+    Ok(())
+}
+
+pub async fn retry(args: &RetryArgs, data_paths: &DataPaths) -> Result<()> {
+    info!("Retrying the quarantined traces...");
+
+    let failures_csv_file = data_paths.failures_csv();
+    let failure_csv_vec = read_csv::<FailureCsv>(&failures_csv_file)?
+        .map(|(_, failure_csv_vec)| failure_csv_vec)
+        .unwrap_or_default();
+    if failure_csv_vec.is_empty() {
+        info!("No quarantined trace in {:?}", failures_csv_file);
+        return Ok(());
+    }
+
+    let retry_data_paths = DataPaths {
+        failures_csv: Some(failures_csv_file.with_extension("csv.retry")),
+        ..data_paths.clone()
+    };
+    let retry_failures_csv_file = retry_data_paths.failures_csv();
+    if retry_failures_csv_file.exists() {
+        std::fs::remove_file(&retry_failures_csv_file)?;
+    }
+
+    let block_timestamp_fetcher = BlockTimestampFetcher::try_new(None, data_paths)?;
+    let trace_cache = TraceCache::try_new(None, args.trace_backend, data_paths.trace_cache_dir())?;
+    let pool = PoolConfig::load(args.pool_config.as_deref())?;
+    let mut swap_fetcher = SwapFetcher::try_new(
+        trace_cache,
+        pool,
+        block_timestamp_fetcher,
+        &retry_data_paths,
+        args.concurrency,
+    )?;
+    swap_fetcher.quarantine = true;
+
+    let failed_tx_hash_trace_paths: HashSet<(String, String)> = failure_csv_vec
+        .iter()
+        .map(|failure_csv| (failure_csv.tx_hash.clone(), failure_csv.trace_path.clone()))
+        .collect();
+    let tx_hashes: BTreeSet<TxHash> = failure_csv_vec
+        .iter()
+        .map(|failure_csv| failure_csv.tx_hash.parse())
+        .collect::<Result<_, _>>()?;
+
+    let mut localized_traces = Vec::new();
+    for tx_hash in tx_hashes {
+        localized_traces.extend(
+            swap_fetcher
+                .trace_cache
+                .fetch_pool_traces(swap_fetcher.pool.pool_address, tx_hash)
+                .await?
+                .into_iter()
+                .filter(|localized_trace| {
+                    failed_tx_hash_trace_paths.contains(&(
+                        tx_hash.to_string(),
+                        localized_trace.trace.trace_address.stringify_vec_usize(),
+                    ))
+                }),
+        );
+    }
+
+    let swap_csv_vec = swap_fetcher.process_traces(localized_traces).await?;
+    swap_fetcher.flush()?;
+    std::fs::rename(&retry_failures_csv_file, &failures_csv_file)?;
+
+    info!(
+        "Retrying the quarantined traces done.({} swaps, {} traces still failing)",
+        swap_csv_vec.len(),
+        read_csv::<FailureCsv>(&failures_csv_file)?
+            .map(|(_, failure_csv_vec)| failure_csv_vec.len())
+            .unwrap_or_default()
+    );
+    Ok(())
+}
+
+async fn connect(rpc_args: &RpcArgs) -> Result<ProviderFiller> {
+    if let Some(fixtures_dir) = &rpc_args.rpc_replay {
+        info!("Replaying rpc responses from {:?}", fixtures_dir);
+        let client = RpcClient::builder().transport(RpcReplay::new(fixtures_dir.clone()), true);
+        return Ok(ProviderBuilder::new().connect_client(client));
+    }
+
+    let rpc_pool = RpcPool::connect(
+        &rpc_args.rpc_url,
+        RetryOptions {
+            max_retry: rpc_args.max_retry,
+            backoff: rpc_args.backoff,
+            cups: rpc_args.cups,
+        },
+    )
+    .await?;
+    let client = match &rpc_args.rpc_record {
+        Some(fixtures_dir) => {
+            info!("Recording rpc responses into {:?}", fixtures_dir);
+            std::fs::create_dir_all(fixtures_dir)?;
+            RpcClient::builder()
+                .layer(RpcRecordLayer::new(fixtures_dir.clone()))
+                .transport(rpc_pool, false)
+        }
+        None => RpcClient::builder().transport(rpc_pool, false),
+    };
+
+    Ok(ProviderBuilder::new().connect_client(client))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::test_helper::temp_data_paths;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct TestArgs {
+        #[command(flatten)]
+        rpc: RpcArgs,
+    }
+
+    #[test]
+    fn test_rpc_args() {
+        assert!(TestArgs::try_parse_from(["test", "--rpc-url", "http://node"]).is_ok());
+        assert!(TestArgs::try_parse_from(["test", "--rpc-replay", "fixtures"]).is_ok());
+        assert!(TestArgs::try_parse_from(["test"]).is_err());
+        assert!(
+            TestArgs::try_parse_from([
+                "test",
+                "--rpc-url",
+                "http://node",
+                "--rpc-replay",
+                "fixtures"
+            ])
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_last_canonical_block() -> Result<()> {
+        // Blocks 10 to 17 stored with their hashes, as the last scanned blocks
+        let data_paths = temp_data_paths("reconcile")?;
+        let stored_hash = |block_number: BlockNumber| B256::with_last_byte(block_number as u8);
+        let blocks_csv = (10..=17).fold(
+            "timestamp,number,hash\n".to_string(),
+            |blocks_csv, block_number| {
+                format!(
+                    "{blocks_csv}{},{block_number},{}\n",
+                    1000 + 5 * block_number,
+                    stored_hash(block_number)
+                )
+            },
+        );
+        std::fs::write(data_paths.blocks_csv(), blocks_csv)?;
+        let block_timestamp_fetcher = BlockTimestampFetcher::try_new(None, &data_paths)?;
+        let block_hashes = block_timestamp_fetcher.block_hashes(10..=17);
+        assert_eq!(block_hashes.len(), 8);
+
+        // The canonical chain forked off the stored blocks at `first_reorged_block`
+        let find = async |first_reorged_block: BlockNumber| {
+            find_last_canonical_block(&block_hashes, 8, async |block_number| {
+                Ok(if block_number < first_reorged_block {
+                    stored_hash(block_number)
+                } else {
+                    B256::repeat_byte(0xff)
+                })
+            })
+            .await
+        };
+        assert_eq!(find(18).await?, None);
+        assert_eq!(find(17).await?, Some(16));
+        assert_eq!(find(14).await?, Some(13));
+        assert_eq!(find(11).await?, Some(10));
+        assert!(
+            find(10)
+                .await
+                .unwrap_err()
+                .to_string()
+                .starts_with("Reorg deeper than the 8 checked blocks, block 10")
+        );
+
+        std::fs::remove_dir_all(&data_paths.data_dir)?;
+        Ok(())
+    }
+}
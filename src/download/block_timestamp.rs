@@ -1,81 +1,262 @@
 use super::ProviderFiller;
-use alloy::primitives::BlockTimestamp;
+use crate::helper::{open_csv, read_csv, rewrite_csv};
+use crate::paths::DataPaths;
+use alloy::eips::BlockNumberOrTag;
+use alloy::primitives::{B256, BlockTimestamp};
 use alloy::providers::Provider;
-use eyre::{Context, ContextCompat, Result};
-use log::info;
-use std::collections::HashMap;
-use std::fs::OpenOptions;
+use alloy::rpc::client::BatchRequest;
+use alloy::rpc::types::Header;
+use eyre::{Context, ContextCompat, OptionExt, Result, bail};
+use log::debug;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 
-const BLOCKS_CSV_FILE: &str = "data/blocks.csv";
+/// Headers requested per JSON-RPC batch, nodes commonly refuse larger batches
+const HEADER_BATCH_SIZE: usize = 100;
+
+/// Gnosis chain slot time in seconds, a missed slot makes the gap between two blocks longer
+const SLOT_TIME: u64 = 5;
+
+/// Without a provider (offline) only the blocks of blocks.csv are known
 pub struct BlockTimestampFetcher {
-    provider: ProviderFiller,
+    provider: Option<ProviderFiller>,
     csv_writer: csv::Writer<std::fs::File>,
+    blocks_csv_file: PathBuf,
     block_timestamp_by_number: HashMap<BlockNumber, Timestamp>,
-    block_number_by_timestamp: HashMap<Timestamp, BlockNumber>,
+    block_number_by_timestamp: BTreeMap<Timestamp, BlockNumber>,
+    block_hash_by_number: BTreeMap<BlockNumber, B256>,
 }
 type Timestamp = u64;
 type BlockNumber = u64;
 impl BlockTimestampFetcher {
-    pub fn try_new(provider: ProviderFiller) -> Result<Self> {
-        let Ok(mut csv_reader) = csv::Reader::from_path(BLOCKS_CSV_FILE) else {
-            let csv_writer = csv::Writer::from_path(BLOCKS_CSV_FILE)?;
-
-            info!("No blocks timestamp file found");
-            return Ok(Self {
-                csv_writer,
-                provider,
-                block_timestamp_by_number: HashMap::new(),
-                block_number_by_timestamp: HashMap::new(),
-            });
+    pub fn try_new(provider: Option<ProviderFiller>, data_paths: &DataPaths) -> Result<Self> {
+        let blocks_csv_file = data_paths.blocks_csv();
+        let (blocks, csv_writer) = open_csv::<BlockWithTimestamp>(&blocks_csv_file)?;
+
+        let mut block_timestamp_fetcher = Self {
+            provider,
+            csv_writer,
+            blocks_csv_file,
+            block_timestamp_by_number: HashMap::new(),
+            block_number_by_timestamp: BTreeMap::new(),
+            block_hash_by_number: BTreeMap::new(),
         };
-        info!("Reading blocks timestamp file...");
-
-        let mut block_timestamp_by_number = HashMap::new();
-        let mut block_number_by_timestamp = HashMap::new();
-        for block in csv_reader.deserialize::<BlockWithTimestamp>() {
-            let block = block?;
-            block_timestamp_by_number.insert(block.number, block.timestamp);
-            block_number_by_timestamp.insert(block.timestamp, block.number);
-        }
-        info!(
-            "Reading blocks timestamp file done.({})",
-            block_timestamp_by_number.len()
-        );
+        block_timestamp_fetcher.insert_blocks(blocks);
 
-        let csv_writer = csv::WriterBuilder::new()
-            .has_headers(false)
-            .from_writer(OpenOptions::new().append(true).open(BLOCKS_CSV_FILE)?);
+        Ok(block_timestamp_fetcher)
+    }
 
-        Ok(Self {
-            csv_writer,
-            provider,
-            block_timestamp_by_number,
-            block_number_by_timestamp,
-        })
+    fn insert_blocks(&mut self, blocks: Vec<BlockWithTimestamp>) {
+        for block in blocks {
+            self.block_timestamp_by_number
+                .insert(block.number, block.timestamp);
+            self.block_number_by_timestamp
+                .insert(block.timestamp, block.number);
+            if let Some(hash) = block.hash {
+                self.block_hash_by_number.insert(block.number, hash);
+            }
+        }
     }
+
     pub async fn fetch_timestamp(&mut self, block_number: u64) -> Result<Timestamp> {
         if let Some(timestamp) = self.block_timestamp_by_number.get(&block_number) {
             return Ok(*timestamp);
         }
 
-        let block_timestamp: Timestamp = self
-            .provider
+        let header = self
+            .provider()?
+            .get_block_by_number(block_number.into())
+            .await?
+            .wrap_err("Block number not found")?
+            .header;
+        let block = BlockWithTimestamp {
+            number: block_number,
+            timestamp: header.timestamp,
+            hash: Some(header.hash),
+        };
+        self.csv_writer.serialize(&block)?;
+        self.insert_blocks(vec![block]);
+
+        Ok(header.timestamp)
+    }
+
+    /// Fetch the headers of the blocks missing from blocks.csv with batched JSON-RPC requests
+    /// (without their transactions) and store their timestamps together
+    pub async fn prefetch_timestamps(
+        &mut self,
+        block_numbers: impl IntoIterator<Item = BlockNumber>,
+    ) -> Result<()> {
+        let missing_block_numbers: BTreeSet<BlockNumber> = block_numbers
+            .into_iter()
+            .filter(|block_number| !self.block_timestamp_by_number.contains_key(block_number))
+            .collect();
+        if missing_block_numbers.is_empty() {
+            return Ok(());
+        }
+        debug!("Prefetching {} block headers", missing_block_numbers.len());
+
+        let missing_block_numbers: Vec<BlockNumber> = missing_block_numbers.into_iter().collect();
+        for block_numbers in missing_block_numbers.chunks(HEADER_BATCH_SIZE) {
+            let mut batch = BatchRequest::new(self.provider()?.client());
+            let waiters = block_numbers
+                .iter()
+                .map(|block_number| {
+                    batch.add_call::<_, Option<Header>>(
+                        "eth_getBlockByNumber",
+                        &(BlockNumberOrTag::Number(*block_number), false),
+                    )
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            batch.send().await.wrap_err(format!(
+                "Failed to send the header batch of blocks {}..={}",
+                block_numbers[0],
+                block_numbers[block_numbers.len() - 1]
+            ))?;
+
+            let mut blocks = Vec::new();
+            for (block_number, waiter) in block_numbers.iter().zip(waiters) {
+                let header = waiter
+                    .await
+                    .wrap_err(format!("Failed to fetch block header {}", block_number))?
+                    .wrap_err(format!("Block number {} not found", block_number))?;
+                blocks.push(BlockWithTimestamp {
+                    number: *block_number,
+                    timestamp: header.timestamp,
+                    hash: Some(header.hash),
+                });
+            }
+            for block in &blocks {
+                self.csv_writer.serialize(block)?;
+            }
+            self.insert_blocks(blocks);
+        }
+
+        Ok(())
+    }
+
+    /// Hashes stored for the blocks of `range`, ordered by block number
+    pub fn block_hashes(&self, range: RangeInclusive<BlockNumber>) -> Vec<(BlockNumber, B256)> {
+        self.block_hash_by_number
+            .range(range)
+            .map(|(block_number, block_hash)| (*block_number, *block_hash))
+            .collect()
+    }
+
+    /// Hash of the block currently in the canonical chain, never read from blocks.csv
+    pub async fn fetch_canonical_hash(&self, block_number: BlockNumber) -> Result<B256> {
+        Ok(self
+            .provider()?
             .get_block_by_number(block_number.into())
             .await?
             .wrap_err("Block number not found")?
             .header
-            .timestamp;
-        self.block_timestamp_by_number
-            .insert(block_number, block_timestamp);
-        self.block_number_by_timestamp
-            .insert(block_timestamp, block_number);
+            .hash)
+    }
 
-        self.csv_writer.serialize(BlockWithTimestamp {
-            number: block_number,
-            timestamp: block_timestamp,
-        })?;
+    /// Forget every block from `from_block`, blocks.csv is rewritten without them
+    pub fn rollback(&mut self, from_block: BlockNumber) -> Result<()> {
+        self.csv_writer.flush()?;
 
-        Ok(block_timestamp)
+        let blocks = read_csv::<BlockWithTimestamp>(&self.blocks_csv_file)?
+            .map(|(_, blocks)| blocks)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|block| block.number < from_block)
+            .collect();
+        let (blocks, csv_writer) = rewrite_csv(&self.blocks_csv_file, blocks)?;
+
+        self.csv_writer = csv_writer;
+        self.block_timestamp_by_number.clear();
+        self.block_number_by_timestamp.clear();
+        self.block_hash_by_number.clear();
+        self.insert_blocks(blocks);
+
+        Ok(())
+    }
+
+    /// Last block whose timestamp is lower or equal to `timestamp`. Answered from the known
+    /// blocks when they bracket it, otherwise searched on chain starting from a guess based on
+    /// the Gnosis slot time, falling back to bisection when the guesses converge slowly. The
+    /// probed blocks are not stored in blocks.csv.
+    pub async fn block_at_or_before(&self, timestamp: Timestamp) -> Result<BlockNumber> {
+        let known_low = self
+            .block_number_by_timestamp
+            .range(..=timestamp)
+            .next_back()
+            .map(|(timestamp, block_number)| (*block_number, *timestamp));
+        let known_high = self
+            .block_number_by_timestamp
+            .range(timestamp.saturating_add(1)..)
+            .next()
+            .map(|(timestamp, block_number)| (*block_number, *timestamp));
+
+        // timestamp(low) <= timestamp < timestamp(high)
+        let (mut low, mut low_timestamp) = match known_low {
+            Some((block_number, block_timestamp)) if block_timestamp == timestamp => {
+                return Ok(block_number);
+            }
+            Some(low) => low,
+            None => {
+                let genesis_timestamp = self.probe_timestamp(0).await?;
+                if genesis_timestamp > timestamp {
+                    bail!("Timestamp {} is before the genesis block", timestamp);
+                }
+                (0, genesis_timestamp)
+            }
+        };
+        let mut high = match known_high {
+            Some((block_number, _)) => block_number,
+            None => {
+                let latest_block = self.provider()?.get_block_number().await?;
+                if self.probe_timestamp(latest_block).await? <= timestamp {
+                    return Ok(latest_block);
+                }
+                latest_block
+            }
+        };
+
+        let mut is_guessing = true;
+        while high - low > 1 {
+            let guess = if is_guessing {
+                low.saturating_add((timestamp - low_timestamp) / SLOT_TIME)
+            } else {
+                low + (high - low) / 2
+            }
+            .clamp(low + 1, high - 1);
+
+            let previous_width = high - low;
+            let guess_timestamp = self.probe_timestamp(guess).await?;
+            if guess_timestamp <= timestamp {
+                (low, low_timestamp) = (guess, guess_timestamp);
+            } else {
+                high = guess;
+            }
+            is_guessing = high - low <= previous_width / 2;
+        }
+
+        Ok(low)
+    }
+
+    /// Timestamp of a known block, otherwise fetched without being stored
+    async fn probe_timestamp(&self, block_number: BlockNumber) -> Result<Timestamp> {
+        if let Some(timestamp) = self.block_timestamp_by_number.get(&block_number) {
+            return Ok(*timestamp);
+        }
+
+        Ok(self
+            .provider()?
+            .get_block_by_number(block_number.into())
+            .await?
+            .wrap_err(format!("Block number {} not found", block_number))?
+            .header
+            .timestamp)
+    }
+
+    fn provider(&self) -> Result<&ProviderFiller> {
+        self.provider
+            .as_ref()
+            .ok_or_eyre("Block not found in blocks.csv (offline)")
     }
 
     pub fn flush(&mut self) -> Result<()> {
@@ -88,6 +269,8 @@ impl BlockTimestampFetcher {
 struct BlockWithTimestamp {
     timestamp: u64,
     number: u64,
+    #[serde(default)]
+    hash: Option<B256>,
 }
 
 pub trait TryIntoBlockTimestamp
@@ -110,3 +293,40 @@ impl TryIntoBlockTimestamp for BlockNumber {
             .wrap_err(format!("Failed to fetch block timestamp {:?}", self))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::test_helper::temp_data_paths;
+
+    #[tokio::test]
+    async fn test_block_at_or_before_from_blocks_csv() -> Result<()> {
+        let data_paths = temp_data_paths("block-timestamp")?;
+        std::fs::write(
+            data_paths.blocks_csv(),
+            "timestamp,number,hash\n100,10,\n105,11,\n115,12,\n",
+        )?;
+
+        // Offline, every answer comes from blocks.csv
+        let block_timestamp_fetcher = BlockTimestampFetcher::try_new(None, &data_paths)?;
+        assert_eq!(block_timestamp_fetcher.block_at_or_before(100).await?, 10);
+        assert_eq!(block_timestamp_fetcher.block_at_or_before(104).await?, 10);
+        assert_eq!(block_timestamp_fetcher.block_at_or_before(114).await?, 11);
+        assert_eq!(block_timestamp_fetcher.block_at_or_before(115).await?, 12);
+        assert!(
+            block_timestamp_fetcher
+                .block_at_or_before(99)
+                .await
+                .is_err()
+        );
+        assert!(
+            block_timestamp_fetcher
+                .block_at_or_before(116)
+                .await
+                .is_err()
+        );
+
+        std::fs::remove_dir_all(&data_paths.data_dir)?;
+        Ok(())
+    }
+}
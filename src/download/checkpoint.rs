@@ -0,0 +1,80 @@
+use alloy::primitives::BlockNumber;
+use eyre::{Result, WrapErr};
+use log::info;
+use std::path::{Path, PathBuf};
+
+/// Contiguous block range fully scanned, only saved once the swaps and blocks of the range are
+/// flushed
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub from_block: BlockNumber,
+    pub to_block: BlockNumber,
+}
+
+impl Checkpoint {
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let Ok(checkpoint) = std::fs::read_to_string(path) else {
+            info!("No checkpoint file found");
+            return Ok(None);
+        };
+
+        let checkpoint: Checkpoint = serde_json::from_str(&checkpoint)
+            .wrap_err(format!("Failed to parse checkpoint {:?}", path))?;
+        info!(
+            "Checkpoint found, blocks {}..={} already scanned",
+            checkpoint.from_block, checkpoint.to_block
+        );
+
+        Ok(Some(checkpoint))
+    }
+
+    /// The range covering both, None when unscanned blocks are left between them
+    pub fn merge(&self, other: &Checkpoint) -> Option<Checkpoint> {
+        if other.from_block > self.to_block.saturating_add(1)
+            || self.from_block > other.to_block.saturating_add(1)
+        {
+            return None;
+        }
+
+        Some(Checkpoint {
+            from_block: self.from_block.min(other.from_block),
+            to_block: self.to_block.max(other.to_block),
+        })
+    }
+
+    /// Write to a temporary file then rename it, a crash never leaves a truncated checkpoint
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp_path = PathBuf::from(path);
+        tmp_path.set_extension("json.tmp");
+
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(self)?)
+            .wrap_err(format!("Failed to write checkpoint {:?}", tmp_path))?;
+        std::fs::rename(&tmp_path, path)
+            .wrap_err(format!("Failed to rename checkpoint {:?}", tmp_path))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let checkpoint = Checkpoint {
+            from_block: 100,
+            to_block: 200,
+        };
+        let range = |from_block, to_block| Checkpoint {
+            from_block,
+            to_block,
+        };
+
+        assert_eq!(checkpoint.merge(&range(201, 300)), Some(range(100, 300)));
+        assert_eq!(checkpoint.merge(&range(50, 99)), Some(range(50, 200)));
+        assert_eq!(checkpoint.merge(&range(150, 160)), Some(range(100, 200)));
+        assert_eq!(checkpoint.merge(&range(202, 300)), None);
+        assert_eq!(checkpoint.merge(&range(0, 98)), None);
+    }
+}
@@ -0,0 +1,225 @@
+use crate::download::ProviderFiller;
+use crate::download::pool::PoolConfig;
+use crate::download::swap::fetch_pool_traces;
+use crate::download::trace_cache::TraceCache;
+use crate::download::trace_window::fetch_adaptive;
+use alloy::primitives::{B256, BlockNumber, TxHash};
+use alloy::providers::Provider;
+use alloy::rpc::types::trace::parity::LocalizedTransactionTrace;
+use alloy::rpc::types::{Filter, TransactionRequest};
+use alloy::sol;
+use alloy::sol_types::{SolCall, SolEvent};
+use eyre::{OptionExt, Result, WrapErr};
+use futures::{StreamExt, TryStreamExt, stream};
+use log::{info, warn};
+use std::collections::HashSet;
+
+sol!(
+    event Swap(bytes32 indexed poolId, address indexed tokenIn, address indexed tokenOut, uint256 amountIn, uint256 amountOut);
+    event PoolBalanceChanged(bytes32 indexed poolId, address indexed liquidityProvider, address[] tokens, int256[] deltas, uint256[] protocolFeeAmounts);
+    function getPoolId() external view returns (bytes32);
+);
+
+/// How the pool calls of a block range are found
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Discovery {
+    /// trace_filter to the pool address
+    #[default]
+    Traces,
+    /// eth_getLogs of the Vault Swap/PoolBalanceChanged events, then trace_transaction of the
+    /// matching transactions only
+    Logs,
+    /// Both, the union is decoded and every transaction found by only one of them is reported
+    Both,
+}
+
+pub struct PoolTraceDiscovery {
+    discovery: Discovery,
+    pool: PoolConfig,
+    pool_id: B256,
+    concurrency: usize,
+}
+
+impl PoolTraceDiscovery {
+    pub async fn try_new(
+        provider: &ProviderFiller,
+        discovery: Discovery,
+        pool: PoolConfig,
+        concurrency: usize,
+    ) -> Result<Self> {
+        let pool_id = match (discovery, pool.pool_id) {
+            (Discovery::Traces, _) => B256::ZERO,
+            (_, Some(pool_id)) => pool_id,
+            (_, None) => {
+                let pool_id = fetch_pool_id(provider, &pool).await?;
+                info!("Pool id {}", pool_id);
+                pool_id
+            }
+        };
+
+        Ok(Self {
+            discovery,
+            pool,
+            pool_id,
+            concurrency,
+        })
+    }
+
+    /// Localized traces calling the pool in the range, ordered by block, transaction and trace
+    /// address. Also return if the range had to be split.
+    pub async fn discover(
+        &self,
+        provider: &ProviderFiller,
+        trace_cache: &TraceCache,
+        from_block: BlockNumber,
+        to_block: BlockNumber,
+    ) -> Result<(Vec<LocalizedTransactionTrace>, bool)> {
+        match self.discovery {
+            Discovery::Traces => {
+                self.discover_by_traces(provider, from_block, to_block)
+                    .await
+            }
+            Discovery::Logs => {
+                self.discover_by_logs(provider, trace_cache, from_block, to_block)
+                    .await
+            }
+            Discovery::Both => {
+                let (mut localized_traces, has_split_traces) = self
+                    .discover_by_traces(provider, from_block, to_block)
+                    .await?;
+                let (localized_traces_by_logs, has_split_logs) = self
+                    .discover_by_logs(provider, trace_cache, from_block, to_block)
+                    .await?;
+
+                let tx_hashes_by_traces = tx_hashes(&localized_traces);
+                let tx_hashes_by_logs = tx_hashes(&localized_traces_by_logs);
+                for tx_hash in tx_hashes_by_traces.difference(&tx_hashes_by_logs) {
+                    warn!("Tx {} found by trace_filter only (reverted?)", tx_hash);
+                }
+                for tx_hash in tx_hashes_by_logs.difference(&tx_hashes_by_traces) {
+                    warn!("Tx {} found by eth_getLogs only", tx_hash);
+                }
+
+                localized_traces.extend(localized_traces_by_logs.into_iter().filter(
+                    |localized_trace| {
+                        localized_trace
+                            .transaction_hash
+                            .is_some_and(|tx_hash| !tx_hashes_by_traces.contains(&tx_hash))
+                    },
+                ));
+                sort_localized_traces(&mut localized_traces);
+
+                Ok((localized_traces, has_split_traces || has_split_logs))
+            }
+        }
+    }
+
+    async fn discover_by_traces(
+        &self,
+        provider: &ProviderFiller,
+        from_block: BlockNumber,
+        to_block: BlockNumber,
+    ) -> Result<(Vec<LocalizedTransactionTrace>, bool)> {
+        fetch_adaptive(
+            "trace_filter",
+            from_block,
+            to_block,
+            &|from_block, to_block| {
+                fetch_pool_traces(provider, self.pool.pool_address, from_block, to_block)
+            },
+        )
+        .await
+    }
+
+    async fn discover_by_logs(
+        &self,
+        provider: &ProviderFiller,
+        trace_cache: &TraceCache,
+        from_block: BlockNumber,
+        to_block: BlockNumber,
+    ) -> Result<(Vec<LocalizedTransactionTrace>, bool)> {
+        let (tx_hashes, has_split) = fetch_adaptive(
+            "eth_getLogs",
+            from_block,
+            to_block,
+            &|from_block, to_block| self.fetch_pool_tx_hashes(provider, from_block, to_block),
+        )
+        .await?;
+
+        let localized_traces_by_tx: Vec<Vec<LocalizedTransactionTrace>> = stream::iter(tx_hashes)
+            .map(|tx_hash| trace_cache.fetch_pool_traces(self.pool.pool_address, tx_hash))
+            .buffered(self.concurrency)
+            .try_collect()
+            .await?;
+
+        Ok((localized_traces_by_tx.concat(), has_split))
+    }
+
+    /// Transactions emitting a Vault Swap or PoolBalanceChanged event of the pool, in chain order
+    async fn fetch_pool_tx_hashes(
+        &self,
+        provider: &ProviderFiller,
+        from_block: BlockNumber,
+        to_block: BlockNumber,
+    ) -> Result<Vec<TxHash>> {
+        let filter = Filter::new()
+            .address(self.pool.vault_address)
+            .event_signature(vec![
+                Swap::SIGNATURE_HASH,
+                PoolBalanceChanged::SIGNATURE_HASH,
+            ])
+            .topic1(self.pool_id)
+            .from_block(from_block)
+            .to_block(to_block);
+        let mut logs = provider.get_logs(&filter).await.wrap_err(format!(
+            "Failed to fetch pool logs from {} to {}",
+            from_block, to_block
+        ))?;
+        logs.sort_by_key(|log| (log.block_number, log.transaction_index, log.log_index));
+
+        let mut tx_hashes = Vec::new();
+        for log in logs {
+            let tx_hash = log.transaction_hash.ok_or_eyre("Log without tx hash")?;
+            if tx_hashes.last() != Some(&tx_hash) {
+                tx_hashes.push(tx_hash);
+            }
+        }
+
+        Ok(tx_hashes)
+    }
+}
+
+async fn fetch_pool_id(provider: &ProviderFiller, pool: &PoolConfig) -> Result<B256> {
+    let output = provider
+        .call(
+            TransactionRequest::default()
+                .to(pool.pool_address)
+                .input(getPoolIdCall {}.abi_encode().into()),
+        )
+        .await
+        .wrap_err("Failed to call getPoolId() on the pool")?;
+
+    Ok(getPoolIdCall::abi_decode_returns(&output)?)
+}
+
+fn tx_hashes(localized_traces: &[LocalizedTransactionTrace]) -> HashSet<TxHash> {
+    localized_traces
+        .iter()
+        .filter_map(|localized_trace| localized_trace.transaction_hash)
+        .collect()
+}
+
+pub fn sort_localized_traces(localized_traces: &mut [LocalizedTransactionTrace]) {
+    localized_traces.sort_by(|a, b| {
+        (
+            a.block_number,
+            a.transaction_position,
+            &a.trace.trace_address,
+        )
+            .cmp(&(
+                b.block_number,
+                b.transaction_position,
+                &b.trace.trace_address,
+            ))
+    });
+}
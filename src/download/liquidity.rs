@@ -0,0 +1,143 @@
+use crate::download::skipped::Skippable;
+use crate::download::swap::Swap;
+use crate::download::trace_log::TraceRow;
+use crate::helper::StringifyArrayUsize;
+use alloy::primitives::{Address, BlockNumber, U256};
+use alloy::rpc::types::trace::parity::LocalizedTransactionTrace;
+use eyre::{OptionExt, Result};
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LiquidityKind {
+    Join,
+    Exit,
+}
+
+/// A join or exit, split into the part proportional to the pool balances and the swap part
+#[derive(Debug, Clone)]
+pub struct LiquidityEvent {
+    pub kind: LiquidityKind,
+    /// Sender of the tokens on a join, of the BPT on an exit
+    pub sender: Address,
+    /// Receiver of the BPT on a join, of the tokens on an exit
+    pub recipient: Address,
+    /// BPT minted on a join, burned on an exit
+    pub bpt_amount: U256,
+    /// Total sent to the pool on a join, received from it on an exit
+    pub sdai_amount: U256,
+    pub eure_amount: U256,
+    /// Part owned through the BPT at the pool balances
+    pub sdai_proportional_amount: U256,
+    pub eure_proportional_amount: U256,
+    /// The rest, traded against the pool
+    pub swap: Skippable<Swap>,
+}
+
+/// A row of liquidity.csv, the ledger of every join and exit. The rows written before the
+/// sender, proportional and swap columns existed keep them empty.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct LiquidityCsv {
+    pub block_number: u64,
+    pub tx_hash: String,
+    pub trace_path: String,
+    pub kind: LiquidityKind,
+    #[serde(default)]
+    pub sender: Option<String>,
+    pub recipient: String,
+    pub bpt_amount: String,
+    pub sdai_amount: String,
+    pub eure_amount: String,
+    #[serde(default)]
+    pub sdai_proportional_amount: Option<String>,
+    #[serde(default)]
+    pub eure_proportional_amount: Option<String>,
+    /// None when the join or exit has no swap part
+    #[serde(default)]
+    pub is_buy_eure: Option<bool>,
+    #[serde(default)]
+    pub sdai_swap_amount: Option<String>,
+    #[serde(default)]
+    pub eure_swap_amount: Option<String>,
+}
+
+impl LiquidityCsv {
+    pub fn try_new(
+        localized_trace: &LocalizedTransactionTrace,
+        liquidity: &LiquidityEvent,
+    ) -> Result<Self> {
+        let (is_buy_eure, sdai_swap_amount, eure_swap_amount) = match &liquidity.swap {
+            Skippable::Kept(swap) => (
+                Some(swap.is_buy_eure),
+                swap.sdai_amount.clone(),
+                swap.eure_amount.clone(),
+            ),
+            Skippable::Skipped(_) => (None, "0".to_string(), "0".to_string()),
+        };
+        Ok(LiquidityCsv {
+            block_number: localized_trace
+                .block_number
+                .ok_or_eyre("Block number is missing")?,
+            tx_hash: localized_trace
+                .transaction_hash
+                .ok_or_eyre("no tx_hash")?
+                .to_string(),
+            trace_path: localized_trace.trace.trace_address.stringify_vec_usize(),
+            kind: liquidity.kind,
+            sender: Some(liquidity.sender.to_string()),
+            recipient: liquidity.recipient.to_string(),
+            bpt_amount: liquidity.bpt_amount.to_string(),
+            sdai_amount: liquidity.sdai_amount.to_string(),
+            eure_amount: liquidity.eure_amount.to_string(),
+            sdai_proportional_amount: Some(liquidity.sdai_proportional_amount.to_string()),
+            eure_proportional_amount: Some(liquidity.eure_proportional_amount.to_string()),
+            is_buy_eure,
+            sdai_swap_amount: Some(sdai_swap_amount),
+            eure_swap_amount: Some(eure_swap_amount),
+        })
+    }
+}
+
+impl TraceRow for LiquidityCsv {
+    fn block_number(&self) -> BlockNumber {
+        self.block_number
+    }
+
+    fn tx_hash(&self) -> &str {
+        &self.tx_hash
+    }
+
+    fn trace_path(&self) -> &str {
+        &self.trace_path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::open_csv;
+
+    #[test]
+    fn test_read_proportional_only_rows() -> Result<()> {
+        let liquidity_csv_file =
+            std::env::temp_dir().join(format!("liquidity-{}.csv", std::process::id()));
+        std::fs::write(
+            &liquidity_csv_file,
+            "block_number,tx_hash,trace_path,kind,recipient,bpt_amount,sdai_amount,eure_amount\n\
+             10,0xaa,0,Join,0x11,20,10,10\n",
+        )?;
+
+        // Rewritten with the current columns, the missing ones stay empty
+        let (liquidity_csv_vec, csv_writer) = open_csv::<LiquidityCsv>(&liquidity_csv_file)?;
+        drop(csv_writer);
+        assert_eq!(liquidity_csv_vec.len(), 1);
+        assert_eq!(liquidity_csv_vec[0].sender, None);
+        assert_eq!(liquidity_csv_vec[0].sdai_proportional_amount, None);
+        assert_eq!(liquidity_csv_vec[0].sdai_swap_amount, None);
+        assert_eq!(
+            std::fs::read_to_string(&liquidity_csv_file)?.lines().nth(1),
+            Some("10,0xaa,0,Join,,0x11,20,10,10,,,,,")
+        );
+
+        std::fs::remove_file(&liquidity_csv_file)?;
+        Ok(())
+    }
+}
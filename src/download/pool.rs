@@ -0,0 +1,85 @@
+use alloy::primitives::{Address, B256, address};
+use eyre::{Result, WrapErr};
+use log::info;
+use std::path::Path;
+
+/// Pool profile describing the analysed Balancer pool, its tokens and the storage slots read
+/// from the vm traces.
+///
+/// `sdai` and `eure` are the two token roles used in swaps.csv (`sdai_amount`, `eure_amount`,
+/// `is_buy_eure`...), another pool maps its own tokens on them.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct PoolConfig {
+    /// Pool address, also the BPT token address
+    pub pool_address: Address,
+    /// Balancer Vault emitting the Swap/PoolBalanceChanged events of the pool
+    #[serde(default = "default_vault_address")]
+    pub vault_address: Address,
+    /// Balancer pool id, read from the pool getPoolId() when missing
+    #[serde(default)]
+    pub pool_id: Option<B256>,
+    pub sdai: TokenConfig,
+    pub eure: TokenConfig,
+    pub swap_fee_percentage_key: B256,
+    /// Storage key of the BPT balance owned by the pool itself
+    pub bpt_balance_pool_key: B256,
+    pub bpt_total_supply_key: B256,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct TokenConfig {
+    pub address: Address,
+    /// Index of the token in the pool balances arrays
+    pub index: usize,
+    /// Storage key of the token rate cache
+    pub price_cache_key: B256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolToken {
+    Sdai,
+    Eure,
+    Bpt,
+}
+
+fn default_vault_address() -> Address {
+    address!("BA12222222228d8Ba445958a75a0704d566BF2C8")
+}
+
+/// The sDAI/EURe pool profile, compiled in so it never drifts from the JSON file
+const DEFAULT_POOL_CONFIG_JSON: &str = include_str!("../../pools/balancer-sdai-eure.json");
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        serde_json::from_str(DEFAULT_POOL_CONFIG_JSON)
+            .expect("pools/balancer-sdai-eure.json is not a valid pool profile")
+    }
+}
+
+impl PoolConfig {
+    /// Load the pool profile from a JSON file, default to the sDAI/EURe pool
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            info!("No pool profile given, using the sDAI/EURe pool");
+            return Ok(PoolConfig::default());
+        };
+
+        info!("Reading pool profile {:?}...", path);
+        let pool_config = serde_json::from_str(
+            &std::fs::read_to_string(path)
+                .wrap_err(format!("Failed to read pool profile {:?}", path))?,
+        )
+        .wrap_err(format!("Failed to parse pool profile {:?}", path))?;
+
+        Ok(pool_config)
+    }
+
+    pub fn token(&self, address: Address) -> Option<PoolToken> {
+        match address {
+            address if address == self.sdai.address => Some(PoolToken::Sdai),
+            address if address == self.eure.address => Some(PoolToken::Eure),
+            address if address == self.pool_address => Some(PoolToken::Bpt),
+            _ => None,
+        }
+    }
+}
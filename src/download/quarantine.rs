@@ -0,0 +1,61 @@
+use crate::download::trace_log::TraceRow;
+use crate::helper::StringifyArrayUsize;
+use alloy::primitives::BlockNumber;
+use alloy::rpc::types::trace::parity::LocalizedTransactionTrace;
+use eyre::{OptionExt, Result};
+use std::path::PathBuf;
+
+/// A row of failures.csv, a pool trace which failed to decode in quarantine mode
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct FailureCsv {
+    pub block_number: u64,
+    pub tx_hash: String,
+    pub trace_path: String,
+    /// Error chain, outermost first, separated by ": "
+    pub error: String,
+    /// Files of the failure bundle, separated by ";"
+    pub trace_files: String,
+}
+
+impl FailureCsv {
+    pub fn try_new(
+        localized_trace: &LocalizedTransactionTrace,
+        error: &eyre::Report,
+        trace_files: &[PathBuf],
+    ) -> Result<Self> {
+        Ok(FailureCsv {
+            block_number: localized_trace
+                .block_number
+                .ok_or_eyre("Block number is missing")?,
+            tx_hash: localized_trace
+                .transaction_hash
+                .ok_or_eyre("no tx_hash")?
+                .to_string(),
+            trace_path: localized_trace.trace.trace_address.stringify_vec_usize(),
+            error: error
+                .chain()
+                .map(|cause| cause.to_string().replace('\n', " "))
+                .collect::<Vec<_>>()
+                .join(": "),
+            trace_files: trace_files
+                .iter()
+                .map(|trace_file| trace_file.display().to_string())
+                .collect::<Vec<_>>()
+                .join(";"),
+        })
+    }
+}
+
+impl TraceRow for FailureCsv {
+    fn block_number(&self) -> BlockNumber {
+        self.block_number
+    }
+
+    fn tx_hash(&self) -> &str {
+        &self.tx_hash
+    }
+
+    fn trace_path(&self) -> &str {
+        &self.trace_path
+    }
+}
@@ -0,0 +1,222 @@
+use crate::helper::{read_json_gz, write_json_gz};
+use alloy::rpc::json_rpc::{RequestPacket, Response, ResponsePacket, SerializedRequest};
+use alloy::transports::{TransportError, TransportErrorKind, TransportFut};
+use log::{debug, warn};
+use serde_json::value::RawValue;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+/// One JSON-RPC call, stored as `<method>-<params_hash>.json.gz` in the fixtures directory.
+/// The request id is not part of the key, a replayed response gets the id of the new request.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct RpcFixture {
+    method: String,
+    params: Option<Box<RawValue>>,
+    response: Response,
+}
+
+fn fixture_file(fixtures_dir: &Path, request: &SerializedRequest) -> PathBuf {
+    fixtures_dir.join(format!(
+        "{}-{}.json.gz",
+        request.method(),
+        request.params_hash()
+    ))
+}
+
+/// Layer saving every response of the wrapped transport into the fixtures directory
+#[derive(Clone)]
+pub struct RpcRecordLayer {
+    fixtures_dir: Arc<PathBuf>,
+}
+
+impl RpcRecordLayer {
+    pub fn new(fixtures_dir: PathBuf) -> Self {
+        Self {
+            fixtures_dir: Arc::new(fixtures_dir),
+        }
+    }
+}
+
+impl<S> Layer<S> for RpcRecordLayer {
+    type Service = RpcRecordService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcRecordService {
+            inner,
+            fixtures_dir: self.fixtures_dir.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RpcRecordService<S> {
+    inner: S,
+    fixtures_dir: Arc<PathBuf>,
+}
+
+impl<S> Service<RequestPacket> for RpcRecordService<S>
+where
+    S: Service<
+            RequestPacket,
+            Response = ResponsePacket,
+            Error = TransportError,
+            Future = TransportFut<'static>,
+        > + Send
+        + 'static,
+{
+    type Response = ResponsePacket;
+    type Error = TransportError;
+    type Future = TransportFut<'static>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request_packet: RequestPacket) -> Self::Future {
+        let fixtures_dir = self.fixtures_dir.clone();
+        let requests = request_packet.requests().to_vec();
+        let response_future = self.inner.call(request_packet);
+
+        Box::pin(async move {
+            let response_packet = response_future.await?;
+
+            let request_by_id: HashMap<_, _> = requests
+                .iter()
+                .map(|request| (request.id(), request))
+                .collect();
+            for response in response_packet.responses() {
+                let Some(request) = request_by_id.get(&response.id) else {
+                    continue;
+                };
+                let path = fixture_file(&fixtures_dir, request);
+                let rpc_fixture = RpcFixture {
+                    method: request.method().to_string(),
+                    params: request.params().map(ToOwned::to_owned),
+                    response: response.clone(),
+                };
+                match write_json_gz(&path, &rpc_fixture) {
+                    Ok(()) => debug!("Recorded {:?}", path),
+                    Err(error) => warn!("Failed to record {:?}: {:?}", path, error),
+                }
+            }
+
+            Ok(response_packet)
+        })
+    }
+}
+
+/// Transport answering from the fixtures directory only, a request never recorded is an error
+#[derive(Clone)]
+pub struct RpcReplay {
+    fixtures_dir: Arc<PathBuf>,
+}
+
+impl RpcReplay {
+    pub fn new(fixtures_dir: PathBuf) -> Self {
+        Self {
+            fixtures_dir: Arc::new(fixtures_dir),
+        }
+    }
+
+    fn replay(&self, request: &SerializedRequest) -> Result<Response, TransportError> {
+        let path = fixture_file(&self.fixtures_dir, request);
+        let rpc_fixture = read_json_gz::<RpcFixture>(&path).map_err(|error| {
+            TransportErrorKind::custom_str(&format!(
+                "No rpc fixture {:?} for {}: {:?}",
+                path,
+                request.method(),
+                error
+            ))
+        })?;
+        debug!("Replayed {:?}", path);
+
+        Ok(Response {
+            id: request.id().clone(),
+            payload: rpc_fixture.response.payload,
+        })
+    }
+}
+
+impl Service<RequestPacket> for RpcReplay {
+    type Response = ResponsePacket;
+    type Error = TransportError;
+    type Future = TransportFut<'static>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request_packet: RequestPacket) -> Self::Future {
+        let response_packet = match &request_packet {
+            RequestPacket::Single(request) => self.replay(request).map(ResponsePacket::Single),
+            RequestPacket::Batch(requests) => requests
+                .iter()
+                .map(|request| self.replay(request))
+                .collect::<Result<Vec<_>, _>>()
+                .map(ResponsePacket::Batch),
+        };
+
+        Box::pin(async move { response_packet })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::providers::{Provider, ProviderBuilder};
+    use alloy::rpc::client::RpcClient;
+    use alloy::rpc::json_rpc::ResponsePayload;
+
+    /// Answer 0x10 to every request
+    #[derive(Clone)]
+    struct FakeNode;
+
+    impl Service<RequestPacket> for FakeNode {
+        type Response = ResponsePacket;
+        type Error = TransportError;
+        type Future = TransportFut<'static>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request_packet: RequestPacket) -> Self::Future {
+            let response_packet = request_packet
+                .requests()
+                .iter()
+                .map(|request| Response {
+                    id: request.id().clone(),
+                    payload: ResponsePayload::Success(
+                        RawValue::from_string("\"0x10\"".into()).unwrap(),
+                    ),
+                })
+                .collect();
+
+            Box::pin(async move { Ok(response_packet) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_then_replay() {
+        let fixtures_dir = std::env::temp_dir().join(format!("rpc-fixture-{}", std::process::id()));
+        std::fs::create_dir_all(&fixtures_dir).unwrap();
+
+        let record_client = RpcClient::builder()
+            .layer(RpcRecordLayer::new(fixtures_dir.clone()))
+            .transport(FakeNode, true);
+        let record_provider = ProviderBuilder::new().connect_client(record_client);
+        assert_eq!(record_provider.get_block_number().await.unwrap(), 16);
+
+        let replay_client =
+            RpcClient::builder().transport(RpcReplay::new(fixtures_dir.clone()), true);
+        let replay_provider = ProviderBuilder::new().connect_client(replay_client);
+        assert_eq!(replay_provider.get_block_number().await.unwrap(), 16);
+        assert_eq!(replay_provider.get_block_number().await.unwrap(), 16);
+        assert!(replay_provider.get_chain_id().await.is_err());
+
+        std::fs::remove_dir_all(&fixtures_dir).unwrap();
+    }
+}
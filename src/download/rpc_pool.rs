@@ -0,0 +1,479 @@
+use alloy::pubsub::PubSubConnect;
+use alloy::rpc::json_rpc::{ErrorPayload, RequestPacket, ResponsePacket};
+use alloy::transports::http::reqwest::Url;
+use alloy::transports::http::{Client, Http};
+use alloy::transports::ipc::IpcConnect;
+use alloy::transports::layers::RetryBackoffLayer;
+use alloy::transports::ws::WsConnect;
+use alloy::transports::{BoxTransport, TransportError, TransportFut};
+use eyre::{Result, WrapErr, bail, eyre};
+use log::{info, warn};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service};
+
+/// JSON-RPC error code of a method the endpoint does not serve
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// Error messages of an endpoint not serving a method, often answered with -32000 or -32602
+/// (trace api disabled, pruned node...)
+const UNSUPPORTED_METHOD_PATTERNS: [&str; 9] = [
+    "not supported",
+    "unsupported",
+    "not available",
+    "not enabled",
+    "does not exist",
+    "method not found",
+    "archive",
+    "missing trie node",
+    "pruned",
+];
+
+/// After a failed request an endpoint is only tried once the healthy ones failed, for this long
+const COOLDOWN: Duration = Duration::from_secs(30);
+
+/// An RPC url given as `<URL>[;cups=<N>][;methods=<prefix>,...]`, the transport is chosen from
+/// the URL scheme: `http(s)://`, `ws(s)://` or `ipc:///<absolute socket path>`. A bare path,
+/// absolute or relative to the working directory, is an IPC socket too.
+#[derive(Debug, Clone)]
+pub struct RpcEndpoint {
+    pub url: Url,
+    /// Compute units per second budget, default to --cups
+    pub cups: Option<u64>,
+    /// Prefixes of the methods routed to this endpoint (e.g. `trace_`), every method when empty
+    pub method_prefixes: Vec<String>,
+}
+
+impl RpcEndpoint {
+    pub fn parse(rpc_url: &str) -> Result<Self> {
+        let mut parts = rpc_url.split(';');
+        let url: Url = match parts.next().unwrap_or_default() {
+            "" => bail!("Empty rpc url {:?}", rpc_url),
+            socket_path if socket_path.starts_with('/') || !socket_path.contains(':') => {
+                let socket_path = std::env::current_dir()?.join(socket_path);
+                format!("ipc://{}", socket_path.display())
+            }
+            url => url.to_string(),
+        }
+        .parse()
+        .wrap_err(format!("Failed to parse rpc url {:?}", rpc_url))?;
+        // ipc://tmp/geth.ipc is the socket /geth.ipc on the host "tmp"
+        if url.scheme() == "ipc" && url.host_str().is_some_and(|host| !host.is_empty()) {
+            bail!(
+                "Ipc rpc url {:?} has a host, use ipc:///<absolute path> or a bare socket path",
+                rpc_url
+            );
+        }
+
+        let mut rpc_endpoint = RpcEndpoint {
+            url,
+            cups: None,
+            method_prefixes: Vec::new(),
+        };
+        for part in parts {
+            match part.split_once('=') {
+                Some(("cups", cups)) => {
+                    rpc_endpoint.cups = Some(
+                        cups.parse()
+                            .wrap_err(format!("Failed to parse cups {:?}", cups))?,
+                    )
+                }
+                Some(("methods", methods)) => {
+                    rpc_endpoint.method_prefixes = methods.split(',').map(String::from).collect()
+                }
+                _ => bail!("Unknown rpc url option {:?} in {:?}", part, rpc_url),
+            }
+        }
+
+        Ok(rpc_endpoint)
+    }
+
+    async fn connect(&self) -> Result<BoxTransport> {
+        Ok(match self.url.scheme() {
+            "http" | "https" => BoxTransport::new(Http::<Client>::new(self.url.clone())),
+            "ws" | "wss" => BoxTransport::new(
+                WsConnect::new(self.url.as_str())
+                    .into_service()
+                    .await
+                    .wrap_err(format!("Failed to connect to {}", self.url))?,
+            ),
+            "ipc" => BoxTransport::new(
+                IpcConnect::new(self.socket_path()?)
+                    .into_service()
+                    .await
+                    .wrap_err(format!("Failed to connect to {}", self.name()))?,
+            ),
+            scheme => bail!("Unsupported rpc url scheme {:?} in {}", scheme, self.url),
+        })
+    }
+
+    /// The endpoint in the logs, without the url path which may hold an api key
+    fn name(&self) -> String {
+        match self.url.scheme() {
+            "ipc" => format!("ipc://{}", self.url.path()),
+            scheme => format!("{}://{}", scheme, self.url.host_str().unwrap_or_default()),
+        }
+    }
+
+    fn socket_path(&self) -> Result<PathBuf> {
+        self.url
+            .to_file_path()
+            .map_err(|()| eyre!("Invalid ipc socket path in {}", self.url))
+    }
+
+    fn serves(&self, method: &str) -> bool {
+        self.method_prefixes.is_empty()
+            || self
+                .method_prefixes
+                .iter()
+                .any(|method_prefix| method.starts_with(method_prefix))
+    }
+}
+
+/// Retry and rate limit options applied to every endpoint
+#[derive(Debug, Clone, Copy)]
+pub struct RetryOptions {
+    pub max_retry: u32,
+    /// Initial backoff in milliseconds
+    pub backoff: u64,
+    /// Default compute units per second budget of an endpoint
+    pub cups: u64,
+}
+
+struct PoolEndpoint {
+    rpc_endpoint: RpcEndpoint,
+    transport: BoxTransport,
+    /// Last failed request, the endpoint is cooling down for COOLDOWN after it
+    failed_at: Mutex<Option<Instant>>,
+}
+
+impl PoolEndpoint {
+    fn new(rpc_endpoint: RpcEndpoint, transport: BoxTransport) -> Self {
+        Self {
+            rpc_endpoint,
+            transport,
+            failed_at: Mutex::new(None),
+        }
+    }
+
+    fn is_cooling_down(&self) -> bool {
+        self.failed_at
+            .lock()
+            .expect("Rpc endpoint lock poisoned")
+            .is_some_and(|failed_at| failed_at.elapsed() < COOLDOWN)
+    }
+
+    fn set_failed(&self, is_failed: bool) {
+        *self.failed_at.lock().expect("Rpc endpoint lock poisoned") = is_failed.then(Instant::now);
+    }
+}
+
+fn is_unsupported_method(error_payload: &ErrorPayload) -> bool {
+    let message = error_payload.message.to_lowercase();
+    error_payload.code == METHOD_NOT_FOUND
+        || UNSUPPORTED_METHOD_PATTERNS
+            .iter()
+            .any(|pattern| message.contains(pattern))
+}
+
+/// Transport routing every request to the endpoints serving its methods, round robin, and
+/// failing over to the next endpoint when one is down or does not serve the method. A failed
+/// endpoint is tried last while cooling down. Each endpoint has its own retry/backoff layer and
+/// compute units budget.
+#[derive(Clone)]
+pub struct RpcPool {
+    endpoints: Arc<Vec<PoolEndpoint>>,
+    next_endpoint: Arc<AtomicUsize>,
+}
+
+impl RpcPool {
+    pub async fn connect(
+        rpc_endpoints: &[RpcEndpoint],
+        retry_options: RetryOptions,
+    ) -> Result<Self> {
+        let mut endpoints = Vec::new();
+        for rpc_endpoint in rpc_endpoints {
+            let cups = rpc_endpoint.cups.unwrap_or(retry_options.cups);
+            info!(
+                "Rpc endpoint {} ({} cups, methods: {:?})",
+                rpc_endpoint.name(),
+                cups,
+                rpc_endpoint.method_prefixes
+            );
+            let retry_layer =
+                RetryBackoffLayer::new(retry_options.max_retry, retry_options.backoff, cups);
+
+            endpoints.push(PoolEndpoint::new(
+                rpc_endpoint.clone(),
+                BoxTransport::new(retry_layer.layer(rpc_endpoint.connect().await?)),
+            ));
+        }
+
+        Ok(Self {
+            endpoints: Arc::new(endpoints),
+            next_endpoint: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Endpoints serving every method of the request, starting with the next one in the round
+    /// robin, the ones cooling down last. Every endpoint when none serves them all.
+    fn candidates(&self, request_packet: &RequestPacket) -> Vec<usize> {
+        let start = self.next_endpoint.fetch_add(1, Ordering::Relaxed);
+        let endpoint_ids: Vec<usize> = (0..self.endpoints.len())
+            .map(|offset| (start + offset) % self.endpoints.len())
+            .collect();
+
+        let serving_ids: Vec<usize> = endpoint_ids
+            .iter()
+            .copied()
+            .filter(|endpoint_id| {
+                request_packet
+                    .method_names()
+                    .all(|method| self.endpoints[*endpoint_id].rpc_endpoint.serves(method))
+            })
+            .collect();
+
+        let (healthy_ids, cooling_down_ids): (Vec<usize>, Vec<usize>) =
+            match serving_ids.is_empty() {
+                true => endpoint_ids,
+                false => serving_ids,
+            }
+            .into_iter()
+            .partition(|endpoint_id| !self.endpoints[*endpoint_id].is_cooling_down());
+
+        [healthy_ids, cooling_down_ids].concat()
+    }
+}
+
+impl Service<RequestPacket> for RpcPool {
+    type Response = ResponsePacket;
+    type Error = TransportError;
+    type Future = TransportFut<'static>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request_packet: RequestPacket) -> Self::Future {
+        let endpoints = self.endpoints.clone();
+        let candidates = self.candidates(&request_packet);
+
+        Box::pin(async move {
+            let mut last_result = None;
+
+            for (attempt, endpoint_id) in candidates.iter().enumerate() {
+                let endpoint = &endpoints[*endpoint_id];
+                let result = endpoint
+                    .transport
+                    .clone()
+                    .call(request_packet.clone())
+                    .await;
+
+                let failure = match &result {
+                    Err(error) => {
+                        endpoint.set_failed(true);
+                        Some(error.to_string())
+                    }
+                    Ok(response_packet) => {
+                        endpoint.set_failed(false);
+                        response_packet
+                            .responses()
+                            .iter()
+                            .filter_map(|response| response.payload.as_error())
+                            .find(|error_payload| is_unsupported_method(error_payload))
+                            .map(|error_payload| error_payload.message.to_string())
+                    }
+                };
+                let Some(failure) = failure else {
+                    return result;
+                };
+                if attempt + 1 < candidates.len() {
+                    warn!(
+                        "Rpc endpoint {} failed ({}), failing over",
+                        endpoint.rpc_endpoint.name(),
+                        failure
+                    );
+                }
+                last_result = Some(result);
+            }
+
+            last_result.expect("RpcPool without endpoint")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::providers::{Provider, ProviderBuilder};
+    use alloy::rpc::client::RpcClient;
+    use alloy::rpc::json_rpc::{Response, ResponsePayload};
+    use alloy::transports::TransportErrorKind;
+    use serde_json::value::RawValue;
+
+    /// A node answering every request the same way, counting the requests
+    #[derive(Clone)]
+    struct FakeNode {
+        answer: Result<&'static str, (i64, &'static str)>,
+        is_down: bool,
+        request_count: Arc<AtomicUsize>,
+    }
+
+    impl Service<RequestPacket> for FakeNode {
+        type Response = ResponsePacket;
+        type Error = TransportError;
+        type Future = TransportFut<'static>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request_packet: RequestPacket) -> Self::Future {
+            self.request_count.fetch_add(1, Ordering::Relaxed);
+            if self.is_down {
+                return Box::pin(async {
+                    Err(TransportErrorKind::custom_str("connection refused"))
+                });
+            }
+
+            let answer = self.answer;
+            let response_packet = request_packet
+                .requests()
+                .iter()
+                .map(|request| Response {
+                    id: request.id().clone(),
+                    payload: match answer {
+                        Ok(result) => ResponsePayload::Success(
+                            RawValue::from_string(format!("\"{result}\"")).unwrap(),
+                        ),
+                        Err((code, message)) => ResponsePayload::Failure(ErrorPayload {
+                            code,
+                            message: message.into(),
+                            data: None,
+                        }),
+                    },
+                })
+                .collect();
+
+            Box::pin(async move { Ok(response_packet) })
+        }
+    }
+
+    fn fake_pool(fake_nodes: &[(&str, FakeNode)]) -> RpcPool {
+        RpcPool {
+            endpoints: Arc::new(
+                fake_nodes
+                    .iter()
+                    .map(|(rpc_url, fake_node)| {
+                        PoolEndpoint::new(
+                            RpcEndpoint::parse(rpc_url).unwrap(),
+                            BoxTransport::new(fake_node.clone()),
+                        )
+                    })
+                    .collect(),
+            ),
+            next_endpoint: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn fake_node(answer: Result<&'static str, (i64, &'static str)>, is_down: bool) -> FakeNode {
+        FakeNode {
+            answer,
+            is_down,
+            request_count: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let rpc_endpoint =
+            RpcEndpoint::parse("https://rpc.example.org/key;cups=300;methods=trace_,debug_")?;
+        assert_eq!(rpc_endpoint.url.as_str(), "https://rpc.example.org/key");
+        assert_eq!(rpc_endpoint.cups, Some(300));
+        assert_eq!(rpc_endpoint.method_prefixes, vec!["trace_", "debug_"]);
+        assert!(rpc_endpoint.serves("trace_filter"));
+        assert!(!rpc_endpoint.serves("eth_blockNumber"));
+
+        let rpc_endpoint = RpcEndpoint::parse("wss://rpc.example.org")?;
+        assert_eq!(rpc_endpoint.url.scheme(), "wss");
+        assert_eq!(rpc_endpoint.cups, None);
+        assert!(rpc_endpoint.serves("eth_blockNumber"));
+
+        let rpc_endpoint = RpcEndpoint::parse("/var/run/geth.ipc")?;
+        assert_eq!(rpc_endpoint.url.scheme(), "ipc");
+        assert_eq!(
+            rpc_endpoint.socket_path()?,
+            PathBuf::from("/var/run/geth.ipc")
+        );
+        assert_eq!(rpc_endpoint.name(), "ipc:///var/run/geth.ipc");
+
+        let rpc_endpoint = RpcEndpoint::parse("ipc:///var/run/geth.ipc;methods=trace_")?;
+        assert_eq!(
+            rpc_endpoint.socket_path()?,
+            PathBuf::from("/var/run/geth.ipc")
+        );
+
+        let rpc_endpoint = RpcEndpoint::parse("data/geth.ipc")?;
+        assert_eq!(
+            rpc_endpoint.socket_path()?,
+            std::env::current_dir()?.join("data/geth.ipc")
+        );
+
+        assert!(RpcEndpoint::parse("ipc://tmp/geth.ipc").is_err());
+        assert!(RpcEndpoint::parse("").is_err());
+
+        assert!(RpcEndpoint::parse("https://rpc.example.org;cups=many").is_err());
+        assert!(RpcEndpoint::parse("https://rpc.example.org;retry=3").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_routing_and_failover() -> Result<()> {
+        let archive = fake_node(Ok("0x10"), false);
+        let full = fake_node(
+            Err((-32000, "trace_filter is not available on this node")),
+            false,
+        );
+        let rpc_pool = fake_pool(&[
+            ("http://full", full.clone()),
+            ("http://archive;methods=trace_", archive.clone()),
+        ]);
+        let provider = ProviderBuilder::new().connect_client(RpcClient::new(rpc_pool, true));
+
+        // eth_ methods are only served by the endpoint without method prefixes
+        assert!(provider.get_block_number().await.is_err());
+        assert_eq!(full.request_count.load(Ordering::Relaxed), 1);
+        assert_eq!(archive.request_count.load(Ordering::Relaxed), 0);
+
+        // Failover on a "not available" error even with a -32000 code
+        let rpc_pool = fake_pool(&[
+            ("http://full", full.clone()),
+            ("http://archive", archive.clone()),
+        ]);
+        let provider = ProviderBuilder::new().connect_client(RpcClient::new(rpc_pool, true));
+        for _ in 0..2 {
+            assert_eq!(provider.get_block_number().await?, 16);
+        }
+        assert_eq!(archive.request_count.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cooldown() -> Result<()> {
+        let down = fake_node(Ok("0x10"), true);
+        let up = fake_node(Ok("0x10"), false);
+        let rpc_pool = fake_pool(&[("http://down", down.clone()), ("http://up", up.clone())]);
+        let provider = ProviderBuilder::new().connect_client(RpcClient::new(rpc_pool, true));
+
+        for _ in 0..4 {
+            assert_eq!(provider.get_block_number().await?, 16);
+        }
+        // Tried first once, then last while cooling down
+        assert_eq!(down.request_count.load(Ordering::Relaxed), 1);
+        assert_eq!(up.request_count.load(Ordering::Relaxed), 4);
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+use crate::download::trace_log::TraceRow;
+use crate::helper::StringifyArrayUsize;
+use alloy::primitives::BlockNumber;
+use alloy::rpc::types::trace::parity::LocalizedTransactionTrace;
+use eyre::{OptionExt, Result};
+
+/// Why a pool interaction is not in swaps.csv
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The pool call reverted
+    TraceError,
+    /// The transaction reverted, even if the pool call itself succeeded
+    TxReverted,
+    /// A create or selfdestruct touching the pool, not a call
+    NotACall,
+    /// The call has no output
+    NoOutput,
+    /// The calldata is not onSwap/onJoinPool/onExitPool (getters, transfers...)
+    UnknownCall,
+    /// The Init join minting the first BPT
+    InitJoin,
+    /// AllTokensInForExactBptOut join, proportional so without any swap
+    ProportionalJoin,
+    /// ExactBptInForAllTokensOut exit, proportional so without any swap
+    ProportionalExit,
+    /// Join or exit matching the pool balances, so without any swap
+    NoSwap,
+}
+
+/// Outcome of a decoding step, either kept for swaps.csv or skipped for skipped.csv
+#[derive(Debug, Clone)]
+pub enum Skippable<T> {
+    Kept(T),
+    Skipped(SkipReason),
+}
+
+/// A row of skipped.csv, every pool interaction left out of swaps.csv with its reason
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct SkippedCsv {
+    pub block_number: u64,
+    pub tx_hash: String,
+    pub trace_path: String,
+    pub reason: SkipReason,
+}
+
+impl SkippedCsv {
+    pub fn try_new(
+        localized_trace: &LocalizedTransactionTrace,
+        reason: SkipReason,
+    ) -> Result<Self> {
+        Ok(SkippedCsv {
+            block_number: localized_trace
+                .block_number
+                .ok_or_eyre("Block number is missing")?,
+            tx_hash: localized_trace
+                .transaction_hash
+                .ok_or_eyre("no tx_hash")?
+                .to_string(),
+            trace_path: localized_trace.trace.trace_address.stringify_vec_usize(),
+            reason,
+        })
+    }
+}
+
+impl TraceRow for SkippedCsv {
+    fn block_number(&self) -> BlockNumber {
+        self.block_number
+    }
+
+    fn tx_hash(&self) -> &str {
+        &self.tx_hash
+    }
+
+    fn trace_path(&self) -> &str {
+        &self.trace_path
+    }
+}
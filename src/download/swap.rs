@@ -1,42 +1,59 @@
 mod on_exit_pool;
 mod on_join_pool;
 mod on_swap;
+#[cfg(test)]
+mod test_fixtures;
 
 use crate::download::block_timestamp::TryIntoBlockTimestamp;
-use crate::download::swap::on_exit_pool::{decode_in_out_on_exit_pool, process_on_exit_pool_trace};
-use crate::download::swap::on_join_pool::{decode_in_out_on_join_pool, process_on_join_pool_trace};
-use crate::download::swap::on_swap::{decode_in_out_on_swap, process_on_swap_trace};
+use crate::download::liquidity::LiquidityCsv;
+use crate::download::pool::PoolConfig;
+use crate::download::quarantine::FailureCsv;
+use crate::download::skipped::{SkipReason, Skippable, SkippedCsv};
+use crate::download::swap::on_exit_pool::{
+    decode_in_out_on_exit_pool, onExitPoolCall, onExitPoolReturn, process_on_exit_pool_trace,
+};
+use crate::download::swap::on_join_pool::{
+    decode_in_out_on_join_pool, onJoinPoolCall, onJoinPoolReturn, process_on_join_pool_trace,
+};
+use crate::download::swap::on_swap::{decode_in_out_on_swap, onSwapCall, process_on_swap_trace};
+use crate::download::trace_cache::TraceCache;
+use crate::download::trace_log::{TraceLog, TraceRow};
 use crate::download::{ProviderFiller, block_timestamp::BlockTimestampFetcher};
 use crate::helper::{
-    DivUp, MulUp, Position, StateBySubPath, StringifyArrayUsize, extract_sub_vm_trace,
-    fetch_sub_vm_trace, save_trace_to_file,
+    DivUp, MulUp, Position, StateBySubPath, StringifyArrayUsize, annotated_trace_json,
+    extract_sub_vm_trace, open_csv, read_csv, rewrite_csv,
 };
+use crate::paths::DataPaths;
+use alloy::consensus::Transaction as _;
 use alloy::primitives::{TxHash, U64};
-use alloy::providers::Provider;
+use alloy::rpc::types::{Transaction, TransactionReceipt};
 use alloy::{
-    primitives::{Address, B256, BlockNumber, U256, address, b256},
+    primitives::{Address, B256, BlockNumber, U256},
     providers::ext::TraceApi,
     rpc::types::trace::filter::TraceFilter,
     rpc::types::trace::parity::LocalizedTransactionTrace,
 };
 use eyre::{Context, OptionExt, Result, bail};
-use log::{debug, info};
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-
-const BALANCER_SDAI_EURE_POOL_ADDRESS: Address =
-    address!("dd439304a77f54b1f7854751ac1169b279591ef7");
-const SDAI_ADDRESS: Address = address!("af204776c7245bF4147c2612BF6e5972Ee483701");
-const SDAI_ARRAY_INDEX: usize = 0;
-const EURE_ADDRESS: Address = address!("cB444e90D8198415266c6a2724b7900fb12FC56E");
-const EURE_ARRAY_INDEX: usize = 1;
-const SWAPS_CSV_FILE: &str = "data/swaps.csv";
+use futures::{StreamExt, TryStreamExt, stream};
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 
 pub struct SwapFetcher {
     pub csv_writer: csv::Writer<std::fs::File>,
-    pub provider: ProviderFiller,
+    pub pool: PoolConfig,
     pub block_timestamp_fetcher: BlockTimestampFetcher,
+    pub trace_cache: TraceCache,
     pub swap_csv_by_tx_hash_trace_path: HashMap<(String, String), SwapCsv>,
+    pub skipped_log: TraceLog<SkippedCsv>,
+    pub liquidity_log: TraceLog<LiquidityCsv>,
+    /// Pool traces which failed to decode, only written in quarantine mode
+    pub failure_log: TraceLog<FailureCsv>,
+    /// Record the decoding failures in failures.csv and continue instead of failing
+    pub quarantine: bool,
+    swaps_csv_file: PathBuf,
+    traces_dir: PathBuf,
+    concurrency: usize,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
@@ -46,6 +63,8 @@ pub struct SwapCsv {
     pub eure_amount: String,
     pub block_number: u64,
     pub block_timestamp: u64,
+    #[serde(default)]
+    pub block_hash: String,
     pub tx_hash: String,
     pub trace_path: String,
     pub sdai_last_update: u64,
@@ -57,6 +76,46 @@ pub struct SwapCsv {
     pub sdai_price_new: String,
     pub eure_price_new: String,
     pub swap_fee_percentage: String,
+    /// Signer of the transaction
+    #[serde(default)]
+    pub tx_from: String,
+    /// Contract called by the transaction, empty for a contract creation
+    #[serde(default)]
+    pub tx_to: String,
+    /// None when the transaction was not cached in an offline rebuild
+    #[serde(default)]
+    pub tx_nonce: Option<u64>,
+    /// Index of the transaction in its block
+    #[serde(default)]
+    pub tx_index: Option<u64>,
+    /// SwapRequest.from on a swap, the sender on a join or exit
+    #[serde(default)]
+    pub sender: String,
+    /// SwapRequest.to on a swap, the recipient on a join or exit
+    #[serde(default)]
+    pub recipient: String,
+}
+
+impl TraceRow for SwapCsv {
+    fn block_number(&self) -> BlockNumber {
+        self.block_number
+    }
+
+    fn tx_hash(&self) -> &str {
+        &self.tx_hash
+    }
+
+    fn trace_path(&self) -> &str {
+        &self.trace_path
+    }
+}
+
+/// Every pool interaction of the decoded traces
+#[derive(Debug, Default)]
+pub struct DecodedTraces {
+    pub swap_csv_vec: Vec<SwapCsv>,
+    pub skipped_csv_vec: Vec<SkippedCsv>,
+    pub liquidity_csv_vec: Vec<LiquidityCsv>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
@@ -68,250 +127,390 @@ pub struct Swap {
 
 impl SwapFetcher {
     pub fn try_new(
-        provider: ProviderFiller,
+        trace_cache: TraceCache,
+        pool: PoolConfig,
         block_timestamp_fetcher: BlockTimestampFetcher,
+        data_paths: &DataPaths,
+        concurrency: usize,
     ) -> Result<Self> {
-        let Ok(mut csv_reader) = csv::Reader::from_path(SWAPS_CSV_FILE) else {
-            let csv_writer = csv::Writer::from_path(SWAPS_CSV_FILE)?;
-
-            info!("No swap file found");
-            return Ok(Self {
-                csv_writer,
-                provider,
-                block_timestamp_fetcher,
-                swap_csv_by_tx_hash_trace_path: HashMap::new(),
-            });
-        };
-        info!("Reading swap file...");
-
-        let mut swap_csv_by_tx_hash_trace_path = HashMap::new();
-        for swap_result in csv_reader.deserialize::<SwapCsv>() {
-            let swap = swap_result?;
-            swap_csv_by_tx_hash_trace_path
-                .insert((swap.tx_hash.clone(), swap.trace_path.clone()), swap);
-        }
-        info!(
-            "Reading swap file done.({})",
-            swap_csv_by_tx_hash_trace_path.len()
-        );
-
-        let csv_writer = csv::WriterBuilder::new()
-            .has_headers(false)
-            .from_writer(OpenOptions::new().append(true).open(SWAPS_CSV_FILE)?);
+        let swaps_csv_file = data_paths.swaps_csv();
+        let (swap_csv_vec, csv_writer) = open_csv::<SwapCsv>(&swaps_csv_file)?;
 
         Ok(Self {
             csv_writer,
-            provider,
+            pool,
             block_timestamp_fetcher,
-            swap_csv_by_tx_hash_trace_path,
+            swap_csv_by_tx_hash_trace_path: index_swap_csv_vec(swap_csv_vec),
+            skipped_log: TraceLog::try_new(data_paths.skipped_csv())?,
+            liquidity_log: TraceLog::try_new(data_paths.liquidity_csv())?,
+            failure_log: TraceLog::try_new(data_paths.failures_csv())?,
+            quarantine: false,
+            swaps_csv_file,
+            trace_cache,
+            traces_dir: data_paths.traces_dir(),
+            concurrency,
         })
     }
 
-    pub async fn fetch_swap_csv(
+    /// Forget every swap, liquidity event, skipped interaction, failure and block from
+    /// `from_block`, swaps.csv, liquidity.csv, skipped.csv, failures.csv and blocks.csv are
+    /// rewritten without them and their transactions are removed from the trace cache
+    pub fn rollback(&mut self, from_block: BlockNumber) -> Result<()> {
+        self.csv_writer.flush()?;
+
+        let swap_csv_vec = read_csv::<SwapCsv>(&self.swaps_csv_file)?
+            .map(|(_, swap_csv_vec)| swap_csv_vec)
+            .unwrap_or_default();
+        let swap_csv_count = swap_csv_vec.len();
+        let swap_csv_vec: Vec<SwapCsv> = swap_csv_vec
+            .into_iter()
+            .filter(|swap_csv| swap_csv.block_number < from_block)
+            .collect();
+        info!(
+            "Rolling back {} swaps from block {}",
+            swap_csv_count - swap_csv_vec.len(),
+            from_block
+        );
+        let (swap_csv_vec, csv_writer) = rewrite_csv(&self.swaps_csv_file, swap_csv_vec)?;
+
+        self.csv_writer = csv_writer;
+        self.swap_csv_by_tx_hash_trace_path = index_swap_csv_vec(swap_csv_vec);
+        self.skipped_log.rollback(from_block)?;
+        self.liquidity_log.rollback(from_block)?;
+        self.failure_log.rollback(from_block)?;
+        self.trace_cache
+            .rollback(self.pool.pool_address, from_block)?;
+
+        self.block_timestamp_fetcher.rollback(from_block)
+    }
+
+    /// Decode the pool traces, skip the ones already in swaps.csv or skipped.csv and append the
+    /// new swaps, joins and exits and skipped interactions. A quarantined trace which now
+    /// decodes leaves failures.csv.
+    pub async fn process_traces(
         &mut self,
-        from_block: BlockNumber,
-        to_block: BlockNumber,
+        localized_traces: Vec<LocalizedTransactionTrace>,
     ) -> Result<Vec<SwapCsv>> {
+        let localized_traces = localized_traces
+            .into_iter()
+            .filter(|localized_trace| {
+                let Some(tx_hash) = localized_trace.transaction_hash else {
+                    return true;
+                };
+                let trace_path = localized_trace.trace.trace_address.stringify_vec_usize();
+                let already_fetched = self
+                    .swap_csv_by_tx_hash_trace_path
+                    .contains_key(&(tx_hash.to_string(), trace_path.clone()))
+                    || self.skipped_log.contains(&tx_hash.to_string(), &trace_path);
+                if already_fetched {
+                    debug!("Skip tx already fetched");
+                }
+                !already_fetched
+            })
+            .collect();
+
+        let DecodedTraces {
+            swap_csv_vec,
+            skipped_csv_vec,
+            liquidity_csv_vec,
+        } = self.decode_traces(localized_traces).await?;
+        let decoded_tx_hash_trace_paths: HashSet<(String, String)> =
+            swap_csv_vec
+                .iter()
+                .map(|swap_csv| (swap_csv.tx_hash.clone(), swap_csv.trace_path.clone()))
+                .chain(skipped_csv_vec.iter().map(|skipped_csv| {
+                    (skipped_csv.tx_hash.clone(), skipped_csv.trace_path.clone())
+                }))
+                .collect();
+        self.failure_log.remove(&decoded_tx_hash_trace_paths)?;
+        for swap_csv in swap_csv_vec.iter() {
+            self.insert_swap_csv(swap_csv.clone())?;
+        }
+        for liquidity_csv in liquidity_csv_vec {
+            self.liquidity_log.insert(liquidity_csv)?;
+        }
+        for skipped_csv in skipped_csv_vec {
+            debug!(
+                "Skip {} {} ({:?})",
+                skipped_csv.tx_hash, skipped_csv.trace_path, skipped_csv.reason
+            );
+            self.skipped_log.insert(skipped_csv)?;
+        }
+
+        Ok(swap_csv_vec)
+    }
+
+    /// Decode every pool call of a single transaction, without writing anything to swaps.csv,
+    /// liquidity.csv or skipped.csv
+    pub async fn inspect_transaction(&mut self, tx_hash: TxHash) -> Result<DecodedTraces> {
         let localized_traces = self
-            .provider
-            .trace_filter(
-                &TraceFilter::default()
-                    .to_address(vec![BALANCER_SDAI_EURE_POOL_ADDRESS])
-                    .from_block(from_block)
-                    .to_block(to_block),
-            )
+            .trace_cache
+            .read_pool_traces(self.pool.pool_address, tx_hash)
             .await?;
 
-        let mut swap_csv_vec = Vec::new();
+        self.decode_traces(localized_traces).await
+    }
 
-        for localized_trace in localized_traces {
-            if localized_trace.trace.error.is_some() {
-                continue;
-            }
-            let tx_hash = localized_trace.transaction_hash.ok_or_eyre("no tx_hash")?;
-            let trace_path = localized_trace.trace.trace_address.stringify_vec_usize();
-            let block_number = localized_trace
-                .block_number
-                .ok_or_eyre("Block number is missing")?;
-            let block_timestamp = block_number
-                .try_into_block_timestamp(&mut self.block_timestamp_fetcher)
+    /// Fetch the receipts and vm traces concurrently, then decode the swaps in the traces order.
+    /// Every pool interaction is either a swap or a skipped interaction.
+    async fn decode_traces(
+        &mut self,
+        localized_traces: Vec<LocalizedTransactionTrace>,
+    ) -> Result<DecodedTraces> {
+        let pool_call_traces: Vec<(LocalizedTransactionTrace, Skippable<FetchedPoolCall>)> =
+            stream::iter(localized_traces)
+                .map(|localized_trace| async {
+                    let pool_call_trace =
+                        fetch_pool_call_trace(&self.trace_cache, localized_trace.clone()).await?;
+                    Ok::<_, eyre::Error>((localized_trace, pool_call_trace))
+                })
+                .buffered(self.concurrency)
+                .try_collect()
                 .await?;
 
-            if self
-                .swap_csv_by_tx_hash_trace_path
-                .contains_key(&(tx_hash.to_string(), trace_path.clone()))
-            {
-                debug!("Skip tx already fetched");
-                continue;
-            }
-
-            if !self
-                .provider
-                .get_transaction_receipt(tx_hash)
-                .await?
-                .ok_or_eyre("Failed to get receipt by hash {tx_hash}")?
-                .status()
-            {
-                debug!("Skip tx due to status");
-                continue;
-            }
+        let mut decoded_traces = DecodedTraces::default();
 
-            let Some(call_action) = localized_trace.trace.action.as_call() else {
-                continue;
+        for (localized_trace, pool_call_trace) in pool_call_traces {
+            let decoded = match pool_call_trace {
+                Skippable::Kept(Ok(pool_call_trace)) => self
+                    .process_pool_call_trace(&pool_call_trace)
+                    .await
+                    .map_err(|error| PoolCallFailure {
+                        localized_trace: pool_call_trace.localized_trace,
+                        pool_call: Some(pool_call_trace.pool_call),
+                        state_by_sub_path: pool_call_trace.state_by_sub_path,
+                        error,
+                    }),
+                Skippable::Kept(Err(pool_call_failure)) => Err(pool_call_failure),
+                Skippable::Skipped(reason) => Ok((Skippable::Skipped(reason), None)),
             };
-            let Some(trace_output) = localized_trace.trace.result.as_ref() else {
-                continue;
+            let (swap_csv, liquidity_csv) = match decoded {
+                Ok(decoded) => decoded,
+                Err(pool_call_failure) => {
+                    self.quarantine_or_fail(pool_call_failure).await?;
+                    continue;
+                }
             };
+            decoded_traces.liquidity_csv_vec.extend(liquidity_csv);
+            match swap_csv {
+                Skippable::Kept(swap_csv) => decoded_traces.swap_csv_vec.push(swap_csv),
+                Skippable::Skipped(reason) => decoded_traces
+                    .skipped_csv_vec
+                    .push(SkippedCsv::try_new(&localized_trace, reason)?),
+            }
+        }
 
-            let on_swap_maybe = decode_in_out_on_swap(call_action, trace_output)?;
-            let on_join_pool_maybe = decode_in_out_on_join_pool(call_action, trace_output)?;
-            let on_exit_pool_maybe = decode_in_out_on_exit_pool(call_action, trace_output)?;
+        Ok(decoded_traces)
+    }
 
-            if on_swap_maybe.is_none()
-                && on_join_pool_maybe.is_none()
-                && on_exit_pool_maybe.is_none()
-            {
-                continue;
-            }
+    /// The swap and, for a join or exit, its liquidity.csv row
+    async fn process_pool_call_trace(
+        &mut self,
+        pool_call_trace: &PoolCallTrace,
+    ) -> Result<(Skippable<SwapCsv>, Option<LiquidityCsv>)> {
+        let PoolCallTrace {
+            localized_trace,
+            tx_hash,
+            pool_call,
+            state_by_sub_path,
+            transaction,
+            receipt,
+        } = pool_call_trace;
+        let trace_path = localized_trace.trace.trace_address.stringify_vec_usize();
+        let block_number = localized_trace
+            .block_number
+            .ok_or_eyre("Block number is missing")?;
+        let block_timestamp = block_number
+            .try_into_block_timestamp(&mut self.block_timestamp_fetcher)
+            .await?;
 
-            let (_, sub_trace_address) = localized_trace
-                .trace
-                .trace_address
-                .split_at(localized_trace.trace.trace_address.len() - 1);
-            let state_by_sub_path = self
-                .fetch_state_by_sub_path(&localized_trace, &tx_hash)
-                .await?;
+        let (_, sub_trace_address) =
+            split_parent_trace_address(&localized_trace.trace.trace_address)?;
 
-            let (sdai_price_cache_info, eure_price_cache_info) =
-                extract_price_cache_info_sdai_eure(&state_by_sub_path, sub_trace_address)?;
-            let swap_fee_percentage =
-                extract_swap_fee(&state_by_sub_path, sub_trace_address)?.to_string();
-
-            let swap_maybe = match (on_swap_maybe, on_join_pool_maybe, on_exit_pool_maybe) {
-                (Some((swap_in, swap_out)), None, None) => {
-                    match process_on_swap_trace(
-                        &state_by_sub_path,
-                        sub_trace_address,
-                        swap_in,
-                        swap_out,
-                    ) {
-                        Ok(Some(swap)) => {
-                            debug!("onSwap() => {:?}", swap);
-                            Some(swap)
-                        }
-                        Err(e) => {
-                            let _ = self.flush();
-                            self.log_processing_failed(&localized_trace, &tx_hash).await;
-                            bail!("Failed to process onSwap trace\n{:?}", e);
-                        }
-                        Ok(None) => None,
-                    }
-                }
-                (None, Some((join_pool_in, join_pool_out)), None) => {
-                    match process_on_join_pool_trace(
-                        &state_by_sub_path,
-                        sub_trace_address,
-                        join_pool_in,
-                        join_pool_out,
-                    ) {
-                        Ok(Some(swap)) => {
-                            debug!("onJoinPool() => {:?}", swap);
-                            Some(swap)
-                        }
-                        Err(e) => {
-                            let _ = self.flush();
-                            self.log_processing_failed(&localized_trace, &tx_hash).await;
-                            bail!("Failed to process onJoinPool trace\n{:?}", e);
-                        }
-                        Ok(None) => None,
-                    }
-                }
-                (None, None, Some((exit_pool_in, exit_pool_out))) => {
-                    match process_on_exit_pool_trace(
-                        &state_by_sub_path,
-                        sub_trace_address,
-                        exit_pool_in,
-                        exit_pool_out,
-                    ) {
-                        Ok(Some(swap)) => {
-                            debug!("onExitPool() => {:?}", swap);
-                            Some(swap)
-                        }
-                        Err(e) => {
-                            let _ = self.flush();
-                            self.log_processing_failed(&localized_trace, &tx_hash).await;
-                            bail!("Failed to process onExitPool trace\n{:?}", e);
-                        }
-                        Ok(None) => None,
-                    }
-                }
-                (None, None, None) => None,
-                _ => bail!("onSwap(), onJoinPool() and onExitPool() are mutually exclusive"),
-            };
+        let (sdai_price_cache_info, eure_price_cache_info) =
+            extract_price_cache_info_sdai_eure(&self.pool, state_by_sub_path, sub_trace_address)?;
+        let swap_fee_percentage =
+            extract_swap_fee(&self.pool, state_by_sub_path, sub_trace_address)?.to_string();
 
-            if let Some(swap) = swap_maybe {
-                let swap_csv = SwapCsv {
-                    is_buy_eure: swap.is_buy_eure,
-                    sdai_amount: swap.sdai_amount,
-                    eure_amount: swap.eure_amount,
-                    block_number,
-                    block_timestamp,
-                    tx_hash: tx_hash.to_string(),
-                    trace_path: trace_path.clone(),
-                    sdai_last_update: sdai_price_cache_info.last_update,
-                    eure_last_update: eure_price_cache_info.last_update,
-                    sdai_duration: sdai_price_cache_info.duration,
-                    eure_duration: eure_price_cache_info.duration,
-                    sdai_price_old: sdai_price_cache_info.price_old,
-                    eure_price_old: eure_price_cache_info.price_old,
-                    sdai_price_new: sdai_price_cache_info.price_new,
-                    eure_price_new: eure_price_cache_info.price_new,
-                    swap_fee_percentage,
-                };
-                self.insert_swap_csv(swap_csv.clone())?;
-                swap_csv_vec.push(swap_csv);
-            }
+        let pool_call_name = pool_call.name();
+        let (swap, liquidity) = match pool_call {
+            PoolCall::Swap(swap_in, swap_out) => process_on_swap_trace(
+                &self.pool,
+                state_by_sub_path,
+                sub_trace_address,
+                swap_in.clone(),
+                *swap_out,
+            )
+            .map(|swap| (swap, None)),
+            PoolCall::JoinPool(join_pool_in, join_pool_out) => process_on_join_pool_trace(
+                &self.pool,
+                state_by_sub_path,
+                sub_trace_address,
+                join_pool_in.clone(),
+                join_pool_out.clone(),
+            )
+            .map(|liquidity| (liquidity.swap.clone(), Some(liquidity))),
+            PoolCall::ExitPool(exit_pool_in, exit_pool_out) => process_on_exit_pool_trace(
+                &self.pool,
+                state_by_sub_path,
+                sub_trace_address,
+                exit_pool_in.clone(),
+                exit_pool_out.clone(),
+            )
+            .map(|liquidity| (liquidity.swap.clone(), Some(liquidity))),
         }
+        .wrap_err(format!("Failed to process {} trace", pool_call_name))?;
+        let liquidity_csv = liquidity
+            .map(|liquidity| LiquidityCsv::try_new(localized_trace, &liquidity))
+            .transpose()?;
+        let swap = match swap {
+            Skippable::Kept(swap) => {
+                debug!("{}() => {:?}", pool_call_name, swap);
+                swap
+            }
+            Skippable::Skipped(reason) => {
+                return Ok((Skippable::Skipped(reason), liquidity_csv));
+            }
+        };
 
-        Ok(swap_csv_vec)
+        let (sender, recipient) = pool_call.sender_recipient();
+        let swap_csv = SwapCsv {
+            is_buy_eure: swap.is_buy_eure,
+            sdai_amount: swap.sdai_amount,
+            eure_amount: swap.eure_amount,
+            block_number,
+            block_timestamp,
+            block_hash: localized_trace
+                .block_hash
+                .map(|block_hash| block_hash.to_string())
+                .unwrap_or_default(),
+            tx_hash: tx_hash.to_string(),
+            trace_path,
+            sdai_last_update: sdai_price_cache_info.last_update,
+            eure_last_update: eure_price_cache_info.last_update,
+            sdai_duration: sdai_price_cache_info.duration,
+            eure_duration: eure_price_cache_info.duration,
+            sdai_price_old: sdai_price_cache_info.price_old,
+            eure_price_old: eure_price_cache_info.price_old,
+            sdai_price_new: sdai_price_cache_info.price_new,
+            eure_price_new: eure_price_cache_info.price_new,
+            swap_fee_percentage,
+            tx_from: receipt.from.to_string(),
+            tx_to: receipt.to.map(|to| to.to_string()).unwrap_or_default(),
+            tx_nonce: transaction.as_ref().map(|transaction| transaction.nonce()),
+            tx_index: receipt.transaction_index,
+            sender: sender.to_string(),
+            recipient: recipient.to_string(),
+        };
+        Ok((Skippable::Kept(swap_csv), liquidity_csv))
     }
 
-    async fn fetch_state_by_sub_path(
-        &self,
-        localized_trace: &LocalizedTransactionTrace,
-        tx_hash: &TxHash,
-    ) -> Result<StateBySubPath> {
-        let (trace_address, _) = localized_trace
-            .trace
-            .trace_address
-            .split_at(localized_trace.trace.trace_address.len() - 1);
-        let vm_trace = fetch_sub_vm_trace(&self.provider, *tx_hash, trace_address).await?;
+    /// Save the failure bundle of a pool call which failed to decode, then record it in
+    /// failures.csv in quarantine mode or fail
+    async fn quarantine_or_fail(&mut self, pool_call_failure: PoolCallFailure) -> Result<()> {
+        let PoolCallFailure {
+            localized_trace,
+            pool_call,
+            state_by_sub_path,
+            error,
+        } = pool_call_failure;
+        let _ = self.flush();
+        let trace_files = self
+            .save_failure_bundle(
+                &localized_trace,
+                pool_call.as_ref(),
+                &state_by_sub_path,
+                &error,
+            )
+            .await;
+        if !self.quarantine {
+            return Err(error);
+        }
 
-        Ok(StateBySubPath::new(&vm_trace))
+        warn!(
+            "Quarantining tx {} trace {}: {:#}",
+            localized_trace.transaction_hash.unwrap_or_default(),
+            localized_trace.trace.trace_address.stringify_vec_usize(),
+            error
+        );
+        self.failure_log
+            .insert(FailureCsv::try_new(&localized_trace, &error, &trace_files)?)?;
+        Ok(())
     }
 
-    async fn log_processing_failed(
+    /// Write the failure bundle of a pool call in <TRACES_DIR>/<TX_HASH>/: the annotated full
+    /// and sub vm traces, the decoded call, its StateBySubPath and the error chain. Never fails,
+    /// a step failing is logged and skipped. Return the written files.
+    async fn save_failure_bundle(
         &self,
         localized_trace: &LocalizedTransactionTrace,
-        tx_hash: &B256,
-    ) {
-        let vm_trace = fetch_sub_vm_trace(&self.provider, *tx_hash, &[])
-            .await
-            .expect("Failed to fetch sub vm trace");
-        save_trace_to_file(vm_trace.clone(), tx_hash, "full")
-            .expect("Failed to save trace to file");
-
-        let (trace_address, _) = localized_trace
+        pool_call: Option<&PoolCall>,
+        state_by_sub_path: &StateBySubPath,
+        error: &eyre::Report,
+    ) -> Vec<PathBuf> {
+        let Some(tx_hash) = localized_trace.transaction_hash else {
+            return Vec::new();
+        };
+        let bundle_dir = self.traces_dir.join(tx_hash.to_string());
+        let trace_name = localized_trace
             .trace
             .trace_address
-            .split_at(localized_trace.trace.trace_address.len() - 1);
-        let sub_vm_trace = extract_sub_vm_trace(vm_trace.clone(), trace_address)
-            .expect("Failed to extract sub vm trace");
-        save_trace_to_file(sub_vm_trace.clone(), tx_hash, "sub")
-            .expect("Failed to save trace to file");
-
-        let state_by_sub_path = StateBySubPath::new(&vm_trace);
-        debug!("{:#?}", &state_by_sub_path);
+            .stringify_vec_usize()
+            .replace(',', "-");
+
+        let mut bundle_files = Vec::new();
+        let mut save = |file_name: String, content: Result<String>| {
+            let file = bundle_dir.join(file_name);
+            let saved = content.and_then(|content| {
+                std::fs::create_dir_all(&bundle_dir)?;
+                std::fs::write(&file, content)?;
+                Ok(())
+            });
+            match saved {
+                Ok(()) => bundle_files.push(file),
+                Err(save_error) => warn!("Failed to save {:?}: {:?}", file, save_error),
+            }
+        };
+
+        save(
+            format!("error-{trace_name}.txt"),
+            Ok(format!("{:?}", error)),
+        );
+        save(
+            format!("call-{trace_name}.json"),
+            serde_json::to_string_pretty(&serde_json::json!({
+                "call": pool_call.map(PoolCall::name),
+                "decoded": format!("{:#?}", pool_call),
+                "action": localized_trace.trace.action,
+                "result": localized_trace.trace.result,
+            }))
+            .map_err(Into::into),
+        );
+        save(
+            format!("state-{trace_name}.json"),
+            serde_json::to_string_pretty(&state_by_sub_path.to_json()).map_err(Into::into),
+        );
+
+        match self.trace_cache.fetch_vm_trace(tx_hash).await {
+            Ok(vm_trace) => {
+                save(
+                    format!("sub-{trace_name}.json"),
+                    split_parent_trace_address(&localized_trace.trace.trace_address)
+                        .and_then(|(trace_address, _)| {
+                            extract_sub_vm_trace(vm_trace.clone(), trace_address)
+                        })
+                        .and_then(annotated_trace_json),
+                );
+                save("full.json".to_string(), annotated_trace_json(vm_trace));
+            }
+            Err(fetch_error) => warn!(
+                "Failed to fetch the vm trace of {} for its failure bundle: {:?}",
+                tx_hash, fetch_error
+            ),
+        }
+
+        info!("Failure bundle of {} saved in {:?}", tx_hash, bundle_dir);
+        bundle_files
     }
 
     fn insert_swap_csv(&mut self, swap_csv: SwapCsv) -> Result<()> {
@@ -327,11 +526,188 @@ impl SwapFetcher {
 
     pub fn flush(&mut self) -> Result<()> {
         self.csv_writer.flush()?;
+        self.skipped_log.flush()?;
+        self.liquidity_log.flush()?;
+        self.failure_log.flush()?;
         self.block_timestamp_fetcher.flush()
     }
 }
 
+fn index_swap_csv_vec(swap_csv_vec: Vec<SwapCsv>) -> HashMap<(String, String), SwapCsv> {
+    swap_csv_vec
+        .into_iter()
+        .map(|swap_csv| {
+            (
+                (swap_csv.tx_hash.clone(), swap_csv.trace_path.clone()),
+                swap_csv,
+            )
+        })
+        .collect()
+}
+
+pub async fn fetch_pool_traces(
+    provider: &ProviderFiller,
+    pool_address: Address,
+    from_block: BlockNumber,
+    to_block: BlockNumber,
+) -> Result<Vec<LocalizedTransactionTrace>> {
+    provider
+        .trace_filter(
+            &TraceFilter::default()
+                .to_address(vec![pool_address])
+                .from_block(from_block)
+                .to_block(to_block),
+        )
+        .await
+        .wrap_err(format!(
+            "Failed to fetch pool traces from {} to {}",
+            from_block, to_block
+        ))
+}
+
+#[derive(Debug)]
+enum PoolCall {
+    Swap(onSwapCall, U256),
+    JoinPool(onJoinPoolCall, onJoinPoolReturn),
+    ExitPool(onExitPoolCall, onExitPoolReturn),
+}
+
+impl PoolCall {
+    fn name(&self) -> &'static str {
+        match self {
+            PoolCall::Swap(..) => "onSwap",
+            PoolCall::JoinPool(..) => "onJoinPool",
+            PoolCall::ExitPool(..) => "onExitPool",
+        }
+    }
+
+    /// SwapRequest.from and to of a swap, sender and recipient of a join or exit
+    fn sender_recipient(&self) -> (Address, Address) {
+        match self {
+            PoolCall::Swap(swap_in, _) => (swap_in.swapRequest.from, swap_in.swapRequest.to),
+            PoolCall::JoinPool(join_pool_in, _) => (join_pool_in.sender, join_pool_in.recipient),
+            PoolCall::ExitPool(exit_pool_in, _) => (exit_pool_in.sender, exit_pool_in.recipient),
+        }
+    }
+}
+
+/// A pool call trace with its vm trace and transaction fetched, or the decoding failure
+type FetchedPoolCall = std::result::Result<PoolCallTrace, PoolCallFailure>;
+
+/// A decoded pool call with the storage state of its parent call and its transaction
+struct PoolCallTrace {
+    localized_trace: LocalizedTransactionTrace,
+    tx_hash: TxHash,
+    pool_call: PoolCall,
+    state_by_sub_path: StateBySubPath,
+    transaction: Option<Transaction>,
+    receipt: TransactionReceipt,
+}
+
+/// A pool call trace which failed to decode, with what was decoded before the failure
+struct PoolCallFailure {
+    localized_trace: LocalizedTransactionTrace,
+    pool_call: Option<PoolCall>,
+    state_by_sub_path: StateBySubPath,
+    error: eyre::Report,
+}
+
+fn decode_pool_call(localized_trace: &LocalizedTransactionTrace) -> Result<Skippable<PoolCall>> {
+    let Some(call_action) = localized_trace.trace.action.as_call() else {
+        return Ok(Skippable::Skipped(SkipReason::NotACall));
+    };
+    let Some(trace_output) = localized_trace.trace.result.as_ref() else {
+        return Ok(Skippable::Skipped(SkipReason::NoOutput));
+    };
+
+    let on_swap_maybe = decode_in_out_on_swap(call_action, trace_output)?;
+    let on_join_pool_maybe = decode_in_out_on_join_pool(call_action, trace_output)?;
+    let on_exit_pool_maybe = decode_in_out_on_exit_pool(call_action, trace_output)?;
+
+    match (on_swap_maybe, on_join_pool_maybe, on_exit_pool_maybe) {
+        (Some((swap_in, swap_out)), None, None) => {
+            Ok(Skippable::Kept(PoolCall::Swap(swap_in, swap_out)))
+        }
+        (None, Some((join_pool_in, join_pool_out)), None) => Ok(Skippable::Kept(
+            PoolCall::JoinPool(join_pool_in, join_pool_out),
+        )),
+        (None, None, Some((exit_pool_in, exit_pool_out))) => Ok(Skippable::Kept(
+            PoolCall::ExitPool(exit_pool_in, exit_pool_out),
+        )),
+        (None, None, None) => Ok(Skippable::Skipped(SkipReason::UnknownCall)),
+        _ => bail!("onSwap(), onJoinPool() and onExitPool() are mutually exclusive"),
+    }
+}
+
+/// Fetch what decoding the pool call needs, only an rpc or cache failure is an error
+async fn fetch_pool_call_trace(
+    trace_cache: &TraceCache,
+    localized_trace: LocalizedTransactionTrace,
+) -> Result<Skippable<FetchedPoolCall>> {
+    if localized_trace.trace.error.is_some() {
+        return Ok(Skippable::Skipped(SkipReason::TraceError));
+    }
+    let tx_hash = localized_trace.transaction_hash.ok_or_eyre("no tx_hash")?;
+
+    let pool_call = match decode_pool_call(&localized_trace) {
+        Ok(Skippable::Kept(pool_call)) => pool_call,
+        Ok(Skippable::Skipped(reason)) => return Ok(Skippable::Skipped(reason)),
+        Err(error) => {
+            return Ok(Skippable::Kept(Err(PoolCallFailure {
+                localized_trace,
+                pool_call: None,
+                state_by_sub_path: StateBySubPath::default(),
+                error,
+            })));
+        }
+    };
+
+    let receipt = trace_cache.fetch_receipt(tx_hash).await?;
+    if !receipt.status() {
+        return Ok(Skippable::Skipped(SkipReason::TxReverted));
+    }
+    let transaction = trace_cache.fetch_transaction(tx_hash).await?;
+
+    let vm_trace = trace_cache.fetch_vm_trace(tx_hash).await?;
+    let vm_trace = match split_parent_trace_address(&localized_trace.trace.trace_address)
+        .and_then(|(trace_address, _)| extract_sub_vm_trace(vm_trace, trace_address))
+        .wrap_err("Failed to extract the vm trace of the pool call parent")
+    {
+        Ok(vm_trace) => vm_trace,
+        Err(error) => {
+            return Ok(Skippable::Kept(Err(PoolCallFailure {
+                localized_trace,
+                pool_call: Some(pool_call),
+                state_by_sub_path: StateBySubPath::default(),
+                error,
+            })));
+        }
+    };
+
+    Ok(Skippable::Kept(Ok(PoolCallTrace {
+        localized_trace,
+        tx_hash,
+        pool_call,
+        state_by_sub_path: StateBySubPath::new(&vm_trace),
+        transaction,
+        receipt,
+    })))
+}
+
+/// Trace address of the parent call and the index of the pool call in it. The pool is called
+/// by the vault, a top-level pool call has no parent.
+fn split_parent_trace_address(trace_address: &[usize]) -> Result<(&[usize], &[usize])> {
+    let parent_len = trace_address
+        .len()
+        .checked_sub(1)
+        .ok_or_eyre("Top-level pool call, without a parent call")?;
+
+    Ok(trace_address.split_at(parent_len))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn compute_bpt_ratio(
+    pool: &PoolConfig,
     state_by_sub_path: &StateBySubPath,
     bpt_in_out: U256,
     is_store: bool,
@@ -340,11 +716,6 @@ fn compute_bpt_ratio(
     bpt_total_supply_trace_address: &[usize],
     bpt_total_supply_position: Position,
 ) -> Result<U256> {
-    const BPT_BALANCE_POOL_STORAGE_KEY: B256 =
-        b256!("7ece16e0df962b5f0d12e93168ea433e7ad6d26c1059a153571c768eab6a5271");
-    const BPT_TOTAL_SUPPLY_STORAGE_KEY: B256 =
-        b256!("0000000000000000000000000000000000000000000000000000000000000002");
-
     let get_storage = match is_store {
         true => StateBySubPath::get_store_value,
         false => StateBySubPath::get_load_value,
@@ -353,7 +724,7 @@ fn compute_bpt_ratio(
     let bpt_balance_pool = U256::from_be_slice(
         get_storage(
             state_by_sub_path,
-            &BPT_BALANCE_POOL_STORAGE_KEY,
+            &pool.bpt_balance_pool_key,
             bpt_balance_pool_trace_address,
             &bpt_balance_pool_position,
         )
@@ -367,7 +738,7 @@ fn compute_bpt_ratio(
     let bpt_total_supply = U256::from_be_slice(
         get_storage(
             state_by_sub_path,
-            &BPT_TOTAL_SUPPLY_STORAGE_KEY,
+            &pool.bpt_total_supply_key,
             bpt_total_supply_trace_address,
             &bpt_total_supply_position,
         )
@@ -388,6 +759,7 @@ fn compute_bpt_ratio(
         .wrap_err("Failed to div_up bpt_swap by bpt_virtual_supply")
 }
 pub fn compute_sdai_eure_from_bpt(
+    pool: &PoolConfig,
     state_by_sub_path: &StateBySubPath,
     sub_trace_address: &[usize],
     bpt_mint_burn: U256,
@@ -395,6 +767,7 @@ pub fn compute_sdai_eure_from_bpt(
     balances: &[U256],
 ) -> Result<(U256, U256)> {
     let bpt_ratio = compute_bpt_ratio(
+        pool,
         state_by_sub_path,
         bpt_mint_burn,
         is_bpt_mint,
@@ -406,10 +779,10 @@ pub fn compute_sdai_eure_from_bpt(
     .wrap_err("Failed to compute bpt ratio")?;
 
     let sdai_balance_pool = balances
-        .get(SDAI_ARRAY_INDEX)
+        .get(pool.sdai.index)
         .ok_or_eyre("sDAI balance of the pool not found")?;
     let eure_balance_pool = balances
-        .get(EURE_ARRAY_INDEX)
+        .get(pool.eure.index)
         .ok_or_eyre("EURe balance of the pool not found")?;
 
     let bpt_hold_sdai = sdai_balance_pool
@@ -469,16 +842,16 @@ impl TryFrom<B256> for PriceCacheInfo {
     }
 }
 pub fn extract_price_cache_info_sdai_eure(
+    pool: &PoolConfig,
     state_by_sub_path: &StateBySubPath,
     sub_trace_address: &[usize],
 ) -> Result<(PriceCacheInfo, PriceCacheInfo)> {
-    const SDAI_PRICE_CACHE_KEY: B256 =
-        b256!("13da86008ba1c6922daee3e07db95305ef49ebced9f5467a0b8613fcc6b343e3");
-    const EURE_PRICE_CACHE_KEY: B256 =
-        b256!("bbc70db1b6c7afd11e79c0fb0051300458f1a3acb8ee9789d9b6b26c61ad9bc7");
-
     let sdai_price_cache = state_by_sub_path
-        .get_load_value(&SDAI_PRICE_CACHE_KEY, sub_trace_address, &Position::Last)
+        .get_load_value(
+            &pool.sdai.price_cache_key,
+            sub_trace_address,
+            &Position::Last,
+        )
         .ok_or_else(|| {
             eyre::eyre!(
                 "Failed to get sDAI price cache for trace_address {:?} in this position {:?}",
@@ -487,7 +860,11 @@ pub fn extract_price_cache_info_sdai_eure(
             )
         })?;
     let eure_price_cache = state_by_sub_path
-        .get_load_value(&EURE_PRICE_CACHE_KEY, sub_trace_address, &Position::Last)
+        .get_load_value(
+            &pool.eure.price_cache_key,
+            sub_trace_address,
+            &Position::Last,
+        )
         .ok_or_else(|| {
             eyre::eyre!(
                 "Failed to get EURe price cache for trace_address {:?} in this position {:?}",
@@ -503,14 +880,12 @@ pub fn extract_price_cache_info_sdai_eure(
 }
 
 pub fn extract_swap_fee(
+    pool: &PoolConfig,
     state_by_sub_path: &StateBySubPath,
     sub_trace_address: &[usize],
 ) -> Result<U256> {
-    const SWAP_FEE_PERCENTAGE_KEY: B256 =
-        b256!("0000000000000000000000000000000000000000000000000000000000000008");
-
     let Some(swap_fee_percentage_value) = state_by_sub_path.get_load_value(
-        &SWAP_FEE_PERCENTAGE_KEY,
+        &pool.swap_fee_percentage_key,
         sub_trace_address,
         &Position::Last,
     ) else {
@@ -528,53 +903,320 @@ pub fn extract_swap_fee(
     Ok(swap_fee_percentage)
 }
 
-/*#[cfg(test)]
+#[cfg(test)]
 mod tests {
+    //! Regression tests replaying the JSON-RPC responses of tests/fixtures/rpc/, recorded with
+    //! `download --rpc-record tests/fixtures/rpc -s <block> -e <block>` and an empty --data-dir
     use super::*;
-    use crate::download::{BACKOFF, CUPS, MAX_RETRY};
+    use crate::download::rpc_fixture::{RpcRecordLayer, RpcReplay};
+    use crate::download::swap::test_fixtures::{
+        BLOCK_NUMBER, BLOCK_TIMESTAMP, E18, RECIPIENT, SENDER, TX_FROM, cache_sdai_to_eure_swap,
+        offline_data_paths, offline_swap_fetcher,
+    };
+    use crate::download::test_helper::temp_data_paths;
+    use crate::download::trace_backend::TraceBackend;
     use alloy::providers::ProviderBuilder;
     use alloy::rpc::client::RpcClient;
-    use alloy::transports::layers::RetryBackoffLayer;
-
-    #[tokio::test]
-    async fn test_process_on_swap_trace() {
-        env_logger::init();
-
-        let rpc_url = std::env::var("RPC_URL").unwrap();
+    use alloy::transports::http::{Client, Http};
 
-        let client = RpcClient::builder()
-            .layer(RetryBackoffLayer::new(MAX_RETRY, BACKOFF, CUPS))
-            .http(rpc_url.parse().unwrap());
+    /// Decode the block from the responses of tests/fixtures/rpc. With RECORD_RPC_URL set to an
+    /// archive node, the responses are fetched from it and recorded there first.
+    async fn replay_block(block_number: BlockNumber) -> Result<Vec<SwapCsv>> {
+        let fixtures_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/rpc");
+        let client = match std::env::var("RECORD_RPC_URL") {
+            Ok(rpc_url) => {
+                std::fs::create_dir_all(&fixtures_dir)?;
+                RpcClient::builder()
+                    .layer(RpcRecordLayer::new(fixtures_dir))
+                    .transport(Http::<Client>::new(rpc_url.parse()?), false)
+            }
+            Err(_) => RpcClient::builder().transport(RpcReplay::new(fixtures_dir), true),
+        };
         let provider = ProviderBuilder::new().connect_client(client);
 
-        let mut block_timestamp_fetcher = BlockTimestampFetcher::try_new(provider.clone()).unwrap();
+        let data_paths = temp_data_paths(&format!("swap-{block_number}"))?;
 
-        let result_aura_multiple_swap = fetch_swap_csv(
+        let pool = PoolConfig::default();
+        let block_timestamp_fetcher =
+            BlockTimestampFetcher::try_new(Some(provider.clone()), &data_paths)?;
+        let trace_cache = TraceCache::try_new(
+            Some(provider.clone()),
+            TraceBackend::default(),
+            data_paths.trace_cache_dir(),
+        )?;
+        let mut swap_fetcher =
+            SwapFetcher::try_new(trace_cache, pool, block_timestamp_fetcher, &data_paths, 1)?;
+
+        let localized_traces = fetch_pool_traces(
             &provider,
-            &mut block_timestamp_fetcher,
-            BlockNumber::from(30649625u64),
-            0,
+            swap_fetcher.pool.pool_address,
+            block_number,
+            block_number,
         )
-        .await;
+        .await?;
+        let swap_csv_vec = swap_fetcher.process_traces(localized_traces).await;
+        swap_fetcher.flush()?;
 
-        assert!(result_aura_multiple_swap.is_ok());
+        std::fs::remove_dir_all(&data_paths.data_dir)?;
+        swap_csv_vec
+    }
 
-        let result_staticcall_eoa = fetch_swap_csv(
-            &provider,
-            &mut block_timestamp_fetcher,
-            BlockNumber::from(30629227u64),
-            0,
-        )
-        .await;
-        assert!(result_staticcall_eoa.is_ok());
+    /// Decoded rows of a replayed block, with the invariants every decoded swap keeps
+    fn assert_swap_csv_vec(swap_csv_vec: &[SwapCsv], block_number: BlockNumber) {
+        assert!(
+            !swap_csv_vec.is_empty(),
+            "No swap decoded in {block_number}"
+        );
+        for swap_csv in swap_csv_vec {
+            assert_eq!(swap_csv.block_number, block_number);
+            assert!(!swap_csv.trace_path.is_empty());
+            assert_ne!(swap_csv.sdai_amount, "0");
+            assert_ne!(swap_csv.eure_amount, "0");
+        }
+    }
 
-        let result_swap_join_in_out = fetch_swap_csv(
-            &provider,
-            &mut block_timestamp_fetcher,
-            BlockNumber::from(30615088u64),
-            0,
+    #[tokio::test]
+    #[ignore = "needs the rpc fixtures of block 30649625, recorded with RECORD_RPC_URL"]
+    async fn test_aura_multiple_swap() -> Result<()> {
+        let swap_csv_vec = replay_block(30649625).await?;
+        assert!(swap_csv_vec.len() > 1);
+        assert_swap_csv_vec(&swap_csv_vec, 30649625);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the rpc fixtures of block 30629227, recorded with RECORD_RPC_URL"]
+    async fn test_staticcall_eoa() -> Result<()> {
+        assert_swap_csv_vec(&replay_block(30629227).await?, 30629227);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the rpc fixtures of block 30615088, recorded with RECORD_RPC_URL"]
+    async fn test_swap_join_in_out() -> Result<()> {
+        assert_swap_csv_vec(&replay_block(30615088).await?, 30615088);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_offline_sdai_to_eure_swap() -> Result<()> {
+        let data_paths = offline_data_paths("offline-swap")?;
+        let tx_hash = TxHash::repeat_byte(0xaa);
+        let localized_trace =
+            cache_sdai_to_eure_swap(&data_paths, tx_hash, 10 * E18, 9 * E18, true)?;
+
+        let mut swap_fetcher = offline_swap_fetcher(&data_paths)?;
+        let swap_csv_vec = swap_fetcher.process_traces(vec![localized_trace]).await?;
+        swap_fetcher.flush()?;
+
+        assert_eq!(swap_csv_vec.len(), 1);
+        let swap_csv = &swap_csv_vec[0];
+        assert!(swap_csv.is_buy_eure);
+        assert_eq!(swap_csv.sdai_amount, (10 * E18).to_string());
+        assert_eq!(swap_csv.eure_amount, (9 * E18).to_string());
+        assert_eq!(swap_csv.trace_path, "0");
+        assert_eq!(swap_csv.block_number, BLOCK_NUMBER);
+        assert_eq!(swap_csv.block_timestamp, BLOCK_TIMESTAMP);
+        assert_eq!(swap_csv.sdai_last_update, 1_700_000_000);
+        assert_eq!(swap_csv.eure_last_update, 1_690_000_000);
+        assert_eq!(swap_csv.sdai_price_new, (11 * E18 / 10).to_string());
+        assert_eq!(swap_csv.eure_price_new, E18.to_string());
+        assert_eq!(swap_csv.sender, SENDER.to_string());
+        assert_eq!(swap_csv.recipient, RECIPIENT.to_string());
+        assert_eq!(swap_csv.tx_from, TX_FROM.to_string());
+        assert_eq!(
+            swap_csv.tx_to,
+            PoolConfig::default().vault_address.to_string()
+        );
+        assert_eq!(swap_csv.tx_nonce, Some(7));
+        assert_eq!(swap_csv.tx_index, Some(3));
+
+        // Written once to swaps.csv, a second pass skips the already fetched trace
+        let swap_csv_vec = swap_fetcher
+            .process_traces(vec![cache_sdai_to_eure_swap(
+                &data_paths,
+                tx_hash,
+                10 * E18,
+                9 * E18,
+                true,
+            )?])
+            .await?;
+        assert!(swap_csv_vec.is_empty());
+        swap_fetcher.flush()?;
+        assert_eq!(
+            read_csv::<SwapCsv>(&data_paths.swaps_csv())?
+                .unwrap()
+                .1
+                .len(),
+            1
+        );
+
+        // Offline, a transaction missing from the cache only leaves the nonce empty
+        let tx_hash = TxHash::repeat_byte(0xab);
+        let localized_trace =
+            cache_sdai_to_eure_swap(&data_paths, tx_hash, 10 * E18, 9 * E18, true)?;
+        std::fs::remove_file(
+            data_paths
+                .trace_cache_dir()
+                .join(format!("{tx_hash}.tx.json.gz")),
+        )?;
+        let swap_csv_vec = swap_fetcher.process_traces(vec![localized_trace]).await?;
+        assert_eq!(swap_csv_vec.len(), 1);
+        assert_eq!(swap_csv_vec[0].tx_from, TX_FROM.to_string());
+        assert_eq!(swap_csv_vec[0].tx_nonce, None);
+        assert_eq!(swap_csv_vec[0].tx_index, Some(3));
+
+        std::fs::remove_dir_all(&data_paths.data_dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_quarantine_then_retry() -> Result<()> {
+        let data_paths = offline_data_paths("quarantine")?;
+        let tx_hash = TxHash::repeat_byte(0xcc);
+        let localized_trace =
+            cache_sdai_to_eure_swap(&data_paths, tx_hash, 10 * E18, 9 * E18, false)?;
+
+        // Without the rate caches the decoding fails, which stops the run unless quarantined
+        let mut swap_fetcher = offline_swap_fetcher(&data_paths)?;
+        assert!(
+            swap_fetcher
+                .process_traces(vec![localized_trace.clone()])
+                .await
+                .is_err()
+        );
+        swap_fetcher.quarantine = true;
+        let swap_csv_vec = swap_fetcher
+            .process_traces(vec![localized_trace.clone()])
+            .await?;
+        assert!(swap_csv_vec.is_empty());
+        swap_fetcher.flush()?;
+        drop(swap_fetcher);
+
+        let failure_csv_vec = read_csv::<FailureCsv>(&data_paths.failures_csv())?
+            .unwrap()
+            .1;
+        assert_eq!(failure_csv_vec.len(), 1);
+        assert_eq!(failure_csv_vec[0].tx_hash, tx_hash.to_string());
+        assert_eq!(failure_csv_vec[0].trace_path, "0");
+        assert!(failure_csv_vec[0].error.contains("price cache"));
+
+        // Once the vm trace is fixed, retry decodes the swap and empties failures.csv, even with
+        // the trace listed twice apart
+        cache_sdai_to_eure_swap(&data_paths, tx_hash, 10 * E18, 9 * E18, true)?;
+        TraceCache::try_new(None, TraceBackend::default(), data_paths.trace_cache_dir())?
+            .store_pool_traces(PoolConfig::default().pool_address, &[localized_trace])?;
+        let other_failure_csv = FailureCsv {
+            tx_hash: TxHash::repeat_byte(0xcd).to_string(),
+            ..failure_csv_vec[0].clone()
+        };
+        rewrite_csv(
+            &data_paths.failures_csv(),
+            vec![
+                failure_csv_vec[0].clone(),
+                other_failure_csv,
+                failure_csv_vec[0].clone(),
+            ],
+        )?;
+        let other_localized_trace = cache_sdai_to_eure_swap(
+            &data_paths,
+            TxHash::repeat_byte(0xcd),
+            10 * E18,
+            9 * E18,
+            true,
+        )?;
+        TraceCache::try_new(None, TraceBackend::default(), data_paths.trace_cache_dir())?
+            .store_pool_traces(PoolConfig::default().pool_address, &[other_localized_trace])?;
+        crate::download::retry(
+            &crate::download::RetryArgs {
+                pool_config: None,
+                concurrency: 1,
+                trace_backend: TraceBackend::Parity,
+            },
+            &data_paths,
         )
-        .await;
-        assert!(result_swap_join_in_out.is_ok());
+        .await?;
+
+        assert!(
+            read_csv::<FailureCsv>(&data_paths.failures_csv())?
+                .map(|(_, failure_csv_vec)| failure_csv_vec)
+                .unwrap_or_default()
+                .is_empty()
+        );
+        let swap_csv_vec = read_csv::<SwapCsv>(&data_paths.swaps_csv())?.unwrap().1;
+        assert_eq!(swap_csv_vec.len(), 2);
+        assert!(
+            swap_csv_vec
+                .iter()
+                .any(|swap_csv| swap_csv.tx_hash == tx_hash.to_string())
+        );
+        assert_eq!(swap_csv_vec[0].sdai_amount, (10 * E18).to_string());
+
+        std::fs::remove_dir_all(&data_paths.data_dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_quarantined_trace_decoded_later() -> Result<()> {
+        let data_paths = offline_data_paths("decoded-later")?;
+        let tx_hash = TxHash::repeat_byte(0xce);
+        let localized_trace =
+            cache_sdai_to_eure_swap(&data_paths, tx_hash, 10 * E18, 9 * E18, false)?;
+
+        let mut swap_fetcher = offline_swap_fetcher(&data_paths)?;
+        swap_fetcher.quarantine = true;
+        swap_fetcher
+            .process_traces(vec![localized_trace.clone()])
+            .await?;
+        swap_fetcher.flush()?;
+        assert_eq!(
+            read_csv::<FailureCsv>(&data_paths.failures_csv())?
+                .unwrap()
+                .1
+                .len(),
+            1
+        );
+
+        // Decoded by a later download, the trace leaves failures.csv
+        cache_sdai_to_eure_swap(&data_paths, tx_hash, 10 * E18, 9 * E18, true)?;
+        let swap_csv_vec = swap_fetcher.process_traces(vec![localized_trace]).await?;
+        swap_fetcher.flush()?;
+        assert_eq!(swap_csv_vec.len(), 1);
+        assert!(
+            read_csv::<FailureCsv>(&data_paths.failures_csv())?
+                .map(|(_, failure_csv_vec)| failure_csv_vec)
+                .unwrap_or_default()
+                .is_empty()
+        );
+
+        std::fs::remove_dir_all(&data_paths.data_dir)?;
+        Ok(())
     }
-}*/
+
+    #[tokio::test]
+    async fn test_top_level_pool_call_quarantined() -> Result<()> {
+        let data_paths = offline_data_paths("top-level")?;
+        let tx_hash = TxHash::repeat_byte(0xcf);
+        let mut localized_trace =
+            cache_sdai_to_eure_swap(&data_paths, tx_hash, 10 * E18, 9 * E18, true)?;
+        localized_trace.trace.trace_address = Vec::new();
+
+        let mut swap_fetcher = offline_swap_fetcher(&data_paths)?;
+        swap_fetcher.quarantine = true;
+        assert!(
+            swap_fetcher
+                .process_traces(vec![localized_trace])
+                .await?
+                .is_empty()
+        );
+        swap_fetcher.flush()?;
+        let failure_csv_vec = read_csv::<FailureCsv>(&data_paths.failures_csv())?
+            .unwrap()
+            .1;
+        assert_eq!(failure_csv_vec.len(), 1);
+        assert!(failure_csv_vec[0].error.contains("Top-level pool call"));
+
+        std::fs::remove_dir_all(&data_paths.data_dir)?;
+        Ok(())
+    }
+}
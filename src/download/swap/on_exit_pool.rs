@@ -1,4 +1,7 @@
-use crate::download::swap::{EURE_ARRAY_INDEX, SDAI_ARRAY_INDEX, Swap, compute_sdai_eure_from_bpt};
+use crate::download::liquidity::{LiquidityEvent, LiquidityKind};
+use crate::download::pool::PoolConfig;
+use crate::download::skipped::{SkipReason, Skippable};
+use crate::download::swap::{Swap, compute_sdai_eure_from_bpt};
 use crate::helper::{Position, StateBySubPath};
 use alloy::primitives::{B256, U256, keccak256};
 use alloy::rpc::types::trace::parity::{CallAction, TraceOutput};
@@ -45,90 +48,163 @@ pub fn decode_in_out_on_exit_pool(
 }
 
 pub fn process_on_exit_pool_trace(
+    pool: &PoolConfig,
     state_by_sub_path: &StateBySubPath,
     sub_trace_address: &[usize],
     exit_pool_in: onExitPoolCall,
     exit_pool_out: onExitPoolReturn,
-) -> Result<Option<Swap>> {
+) -> Result<LiquidityEvent> {
     let exit_kind: ExitKind = exit_pool_in
         .userData
         .get(0..32)
         .ok_or_eyre("JoinKind not found in userData")?
         .try_into()?;
+    let sdai_received = *exit_pool_out
+        ._0
+        .get(pool.sdai.index)
+        .ok_or_eyre("sDAI output not found in on_exit_pool result")?;
+    let eure_received = *exit_pool_out
+        ._0
+        .get(pool.eure.index)
+        .ok_or_eyre("EURe output not found in on_exit_pool result")?;
 
-    match exit_kind {
-        ExitKind::ExactBptInForOneTokenOut => compute_exit_pool_exact_bpt_to_one_asset(
-            state_by_sub_path,
-            sub_trace_address,
-            &exit_pool_in,
-            &exit_pool_out,
-        ),
-        ExitKind::BptInForExactTokensOut => compute_exit_pool_bpt_to_exact_assets(
-            state_by_sub_path,
-            sub_trace_address,
-            &exit_pool_in,
-            &exit_pool_out,
-        ),
+    let (bpt_burned, (sdai_from_bpt, eure_from_bpt), swap) = match exit_kind {
+        ExitKind::ExactBptInForOneTokenOut => {
+            let bpt_burned = decode_bpt_in(&exit_pool_in)?;
+            let sdai_eure_from_bpt = compute_sdai_eure_from_exit_bpt(
+                pool,
+                state_by_sub_path,
+                sub_trace_address,
+                &exit_pool_in,
+                bpt_burned,
+            )?;
+            (
+                bpt_burned,
+                sdai_eure_from_bpt,
+                compute_exit_pool_exact_bpt_to_one_asset(
+                    sdai_received,
+                    eure_received,
+                    sdai_eure_from_bpt,
+                )?,
+            )
+        }
+        ExitKind::BptInForExactTokensOut => {
+            let bpt_burned =
+                compute_bpt_burned(state_by_sub_path, sub_trace_address, &exit_pool_in)?;
+            let sdai_eure_from_bpt = compute_sdai_eure_from_exit_bpt(
+                pool,
+                state_by_sub_path,
+                sub_trace_address,
+                &exit_pool_in,
+                bpt_burned,
+            )?;
+            (
+                bpt_burned,
+                sdai_eure_from_bpt,
+                compute_exit_pool_bpt_to_exact_assets(&exit_pool_in, sdai_eure_from_bpt)?,
+            )
+        }
         ExitKind::ExactBptInForAllTokensOut => {
             debug!("Skip exit pool to all token, no swap done");
-            Ok(None)
+            (
+                decode_bpt_in(&exit_pool_in)?,
+                (sdai_received, eure_received),
+                Skippable::Skipped(SkipReason::ProportionalExit),
+            )
         }
-    }
+    };
+
+    Ok(LiquidityEvent {
+        kind: LiquidityKind::Exit,
+        sender: exit_pool_in.sender,
+        recipient: exit_pool_in.recipient,
+        bpt_amount: bpt_burned,
+        sdai_amount: sdai_received,
+        eure_amount: eure_received,
+        sdai_proportional_amount: sdai_from_bpt,
+        eure_proportional_amount: eure_from_bpt,
+        swap,
+    })
 }
 
-fn compute_exit_pool_exact_bpt_to_one_asset(
-    state_by_sub_path: &StateBySubPath,
-    sub_trace_address: &[usize],
-    exit_pool_in: &onExitPoolCall,
-    exit_pool_out: &onExitPoolReturn,
-) -> Result<Option<Swap>> {
-    let is_bpt_mint = false;
-    let bpt_sent: U256 = U256::try_from_be_slice(
+/// BPT amount in, from the userData of the exits with an exact BPT in
+fn decode_bpt_in(exit_pool_in: &onExitPoolCall) -> Result<U256> {
+    U256::try_from_be_slice(
         exit_pool_in
             .userData
             .get(32..64)
             .ok_or_eyre("bpt amount sent not found in userData")?,
     )
-    .ok_or_eyre("bpt amount sent cant be convert to U256")?;
+    .ok_or_eyre("bpt amount sent cant be convert to U256")
+}
+
+/// BPT burned from the sender, from its balance before and after the exit
+fn compute_bpt_burned(
+    state_by_sub_path: &StateBySubPath,
+    sub_trace_address: &[usize],
+    exit_pool_in: &onExitPoolCall,
+) -> Result<U256> {
+    let balance_sender_key = {
+        let mut key = B256::left_padding_from(&exit_pool_in.sender.0.0).to_vec();
+        key.extend_from_slice(&B256::ZERO.0);
+        keccak256(key)
+    };
+    let bpt_owned_before: U256 = state_by_sub_path
+        .get_load_value(&balance_sender_key, sub_trace_address, &Position::First)
+        .ok_or_eyre("BPT owned before not found")?
+        .into();
+    let bpt_owned_after: U256 = state_by_sub_path
+        .get_store_value(&balance_sender_key, sub_trace_address, &Position::First)
+        .ok_or_eyre("BPT owned after not found")?
+        .into();
+    bpt_owned_before
+        .checked_sub(bpt_owned_after)
+        .ok_or_eyre("BPT owned increased after a onExitPool")
+}
 
-    let (sdai_from_bpt, eure_from_bpt) = compute_sdai_eure_from_bpt(
+/// sDAI and EURe owned through the BPT burned, at the pool balances before the exit
+fn compute_sdai_eure_from_exit_bpt(
+    pool: &PoolConfig,
+    state_by_sub_path: &StateBySubPath,
+    sub_trace_address: &[usize],
+    exit_pool_in: &onExitPoolCall,
+    bpt_burned: U256,
+) -> Result<(U256, U256)> {
+    let is_bpt_mint = false;
+    compute_sdai_eure_from_bpt(
+        pool,
         state_by_sub_path,
         sub_trace_address,
-        bpt_sent,
+        bpt_burned,
         is_bpt_mint,
         &exit_pool_in.balances,
     )
-    .wrap_err("Failed to compute the amount of sdai/eure from bpt ownership")?;
-
-    let (sdai_received, eure_received) = (
-        exit_pool_out
-            ._0
-            .get(SDAI_ARRAY_INDEX)
-            .ok_or_eyre("sDAI output not found in on_exit_pool result")?,
-        exit_pool_out
-            ._0
-            .get(EURE_ARRAY_INDEX)
-            .ok_or_eyre("EURe output not found in on_exit_pool result")?,
-    );
+    .wrap_err("Failed to compute the amount of sdai/eure from bpt ownership")
+}
 
+fn compute_exit_pool_exact_bpt_to_one_asset(
+    sdai_received: U256,
+    eure_received: U256,
+    (sdai_from_bpt, eure_from_bpt): (U256, U256),
+) -> Result<Skippable<Swap>> {
     match (sdai_received, eure_received) {
-        (sdai_received, &U256::ZERO) => {
+        (sdai_received, U256::ZERO) => {
             let sdai_swapped_from_bpt = sdai_received.checked_sub(sdai_from_bpt).ok_or_eyre(
                 "The amount of sDAI received is less than the amount of sDAI from BPT ownership",
             )?;
 
-            Ok(Some(Swap {
+            Ok(Skippable::Kept(Swap {
                 is_buy_eure: false,
                 sdai_amount: sdai_swapped_from_bpt.to_string(),
                 eure_amount: eure_from_bpt.to_string(),
             }))
         }
-        (&U256::ZERO, eure_received) => {
+        (U256::ZERO, eure_received) => {
             let eure_swapped_from_bpt = eure_received.checked_sub(eure_from_bpt).ok_or_eyre(
                 "The amount of EURe received is less than the amount of EURe from BPT ownership",
             )?;
 
-            Ok(Some(Swap {
+            Ok(Skippable::Kept(Swap {
                 is_buy_eure: true,
                 sdai_amount: sdai_from_bpt.to_string(),
                 eure_amount: eure_swapped_from_bpt.to_string(),
@@ -139,38 +215,9 @@ fn compute_exit_pool_exact_bpt_to_one_asset(
 }
 
 fn compute_exit_pool_bpt_to_exact_assets(
-    state_by_sub_path: &StateBySubPath,
-    sub_trace_address: &[usize],
     exit_pool_in: &onExitPoolCall,
-    _: &onExitPoolReturn,
-) -> Result<Option<Swap>> {
-    let is_bpt_mint = false;
-    let balance_sender_key = {
-        let mut key = B256::left_padding_from(&exit_pool_in.sender.0.0).to_vec();
-        key.extend_from_slice(&B256::ZERO.0);
-        keccak256(key)
-    };
-    let bpt_owned_before: U256 = state_by_sub_path
-        .get_load_value(&balance_sender_key, sub_trace_address, &Position::First)
-        .ok_or_eyre("BPT owned before not found")?
-        .into();
-    let bpt_owned_after: U256 = state_by_sub_path
-        .get_store_value(&balance_sender_key, sub_trace_address, &Position::First)
-        .ok_or_eyre("BPT owned after not found")?
-        .into();
-    let bpt_burned = bpt_owned_before
-        .checked_sub(bpt_owned_after)
-        .ok_or_eyre("BPT owned increased after a onExitPool")?;
-
-    let (sdai_from_bpt, eure_from_bpt) = compute_sdai_eure_from_bpt(
-        state_by_sub_path,
-        sub_trace_address,
-        bpt_burned,
-        is_bpt_mint,
-        &exit_pool_in.balances,
-    )
-    .wrap_err("Failed to compute the amount of sdai/eure from bpt ownership")?;
-
+    (sdai_from_bpt, eure_from_bpt): (U256, U256),
+) -> Result<Skippable<Swap>> {
     let sdai_received: U256 = U256::try_from_be_slice(
         exit_pool_in
             .userData
@@ -192,7 +239,7 @@ fn compute_exit_pool_bpt_to_exact_assets(
                 "BPT => sDAI, but sDAI received is less then the amount from BPT ownership",
             )?;
 
-            Ok(Some(Swap {
+            Ok(Skippable::Kept(Swap {
                 is_buy_eure: false,
                 sdai_amount: sdai_swapped_from_bpt.to_string(),
                 eure_amount: eure_from_bpt.to_string(),
@@ -203,7 +250,7 @@ fn compute_exit_pool_bpt_to_exact_assets(
                 "BPT => EURe, but EURe received is less then the amount from BPT ownership",
             )?;
 
-            Ok(Some(Swap {
+            Ok(Skippable::Kept(Swap {
                 is_buy_eure: true,
                 sdai_amount: sdai_from_bpt.to_string(),
                 eure_amount: eure_swapped_from_bpt.to_string(),
@@ -217,7 +264,7 @@ fn compute_exit_pool_bpt_to_exact_assets(
                 "BPT => +sDAI| -EURe, but EURe received is bigger then the amount from BPT ownership",
             )?;
 
-            Ok(Some(Swap {
+            Ok(Skippable::Kept(Swap {
                 is_buy_eure: false,
                 sdai_amount: sdai_swapped_from_bpt.to_string(),
                 eure_amount: eure_swapped_from_bpt.to_string(),
@@ -231,7 +278,7 @@ fn compute_exit_pool_bpt_to_exact_assets(
                 "BPT => -sDAI| +EURe, but EURe received is less then the amount from BPT ownership",
             )?;
 
-            Ok(Some(Swap {
+            Ok(Skippable::Kept(Swap {
                 is_buy_eure: true,
                 sdai_amount: sdai_swapped_from_bpt.to_string(),
                 eure_amount: eure_swapped_from_bpt.to_string(),
@@ -240,3 +287,115 @@ fn compute_exit_pool_bpt_to_exact_assets(
         _ => Err(eyre!("Unknown assets received")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::swap::test_fixtures::{
+        self, E18, bpt_balance_zero_address_key, exit_pool,
+    };
+
+    /// 200 BPT supply before the exit, none owned by the pool, the sender owning `bpt_before`
+    /// then `bpt_after`
+    fn state_by_sub_path(pool: &PoolConfig, bpt_before: u128, bpt_after: u128) -> StateBySubPath {
+        test_fixtures::state_by_sub_path(
+            &[
+                (pool.bpt_balance_pool_key, &[], 0),
+                (pool.bpt_total_supply_key, &[0], 200 * E18),
+                (bpt_balance_zero_address_key(), &[0], bpt_before),
+            ],
+            &[(bpt_balance_zero_address_key(), &[0], bpt_after)],
+        )
+    }
+
+    #[test]
+    fn test_exact_bpt_in_for_one_token_out() -> Result<()> {
+        let pool = PoolConfig::default();
+
+        // 20 BPT for 21 sDAI, 10% of the pool before the exit: 10 sDAI and 10 EURe, the 10
+        // EURe sold for 11 sDAI
+        let (exit_pool_in, exit_pool_out) = exit_pool(&[0, 20 * E18, 0], [21 * E18, 0]);
+        let liquidity_event = process_on_exit_pool_trace(
+            &pool,
+            &state_by_sub_path(&pool, 0, 0),
+            &[0],
+            exit_pool_in,
+            exit_pool_out,
+        )?;
+        assert_eq!(liquidity_event.kind, LiquidityKind::Exit);
+        assert_eq!(liquidity_event.bpt_amount, U256::from(20 * E18));
+        assert_eq!(liquidity_event.sdai_amount, U256::from(21 * E18));
+        assert_eq!(liquidity_event.eure_amount, U256::ZERO);
+        assert_eq!(
+            liquidity_event.sdai_proportional_amount,
+            U256::from(10 * E18)
+        );
+        assert_eq!(
+            liquidity_event.eure_proportional_amount,
+            U256::from(10 * E18)
+        );
+        let Skippable::Kept(swap) = liquidity_event.swap else {
+            panic!("ExactBptInForOneTokenOut exit skipped");
+        };
+        assert!(!swap.is_buy_eure);
+        assert_eq!(swap.sdai_amount, (11 * E18).to_string());
+        assert_eq!(swap.eure_amount, (10 * E18).to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bpt_in_for_exact_tokens_out() -> Result<()> {
+        let pool = PoolConfig::default();
+
+        // 20 BPT burned for 15 sDAI and 5 EURe: 10 sDAI and 10 EURe from the BPT, 5 EURe sold
+        // for 5 sDAI. userData: kind, amountsOut offset, maxBPTAmountIn, amountsOut
+        let (exit_pool_in, exit_pool_out) = exit_pool(
+            &[1, 0x60, 25 * E18, 2, 15 * E18, 5 * E18],
+            [15 * E18, 5 * E18],
+        );
+        let liquidity_event = process_on_exit_pool_trace(
+            &pool,
+            &state_by_sub_path(&pool, 50 * E18, 30 * E18),
+            &[0],
+            exit_pool_in,
+            exit_pool_out,
+        )?;
+        assert_eq!(liquidity_event.bpt_amount, U256::from(20 * E18));
+        assert_eq!(liquidity_event.sdai_amount, U256::from(15 * E18));
+        assert_eq!(liquidity_event.eure_amount, U256::from(5 * E18));
+        let Skippable::Kept(swap) = liquidity_event.swap else {
+            panic!("BptInForExactTokensOut exit skipped");
+        };
+        assert!(!swap.is_buy_eure);
+        assert_eq!(swap.sdai_amount, (5 * E18).to_string());
+        assert_eq!(swap.eure_amount, (5 * E18).to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exact_bpt_in_for_all_tokens_out() -> Result<()> {
+        let pool = PoolConfig::default();
+        let (exit_pool_in, exit_pool_out) = exit_pool(&[2, 20 * E18], [10 * E18, 10 * E18]);
+
+        let liquidity_event = process_on_exit_pool_trace(
+            &pool,
+            &state_by_sub_path(&pool, 0, 0),
+            &[0],
+            exit_pool_in,
+            exit_pool_out,
+        )?;
+        assert_eq!(liquidity_event.bpt_amount, U256::from(20 * E18));
+        assert_eq!(
+            liquidity_event.sdai_proportional_amount,
+            U256::from(10 * E18)
+        );
+        assert!(matches!(
+            liquidity_event.swap,
+            Skippable::Skipped(SkipReason::ProportionalExit)
+        ));
+
+        Ok(())
+    }
+}
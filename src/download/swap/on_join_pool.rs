@@ -1,4 +1,7 @@
-use crate::download::swap::{EURE_ARRAY_INDEX, SDAI_ARRAY_INDEX, Swap, compute_sdai_eure_from_bpt};
+use crate::download::liquidity::{LiquidityEvent, LiquidityKind};
+use crate::download::pool::PoolConfig;
+use crate::download::skipped::{SkipReason, Skippable};
+use crate::download::swap::{Swap, compute_sdai_eure_from_bpt};
 use crate::helper::{Position, StateBySubPath};
 use alloy::primitives::{B256, U256, keccak256};
 use alloy::rpc::types::trace::parity::{CallAction, TraceOutput};
@@ -12,6 +15,9 @@ sol!(
     function onJoinPool(bytes32 poolId, address sender, address recipient, uint256[] memory balances, uint256 lastChangeBlock, uint256 protocolSwapFeePercentage, bytes memory userData) external virtual returns (uint256[] memory, uint256[] memory);
 );
 
+/// BPT minted to the zero address by the init join, as in BasePool
+const MINIMUM_BPT: U256 = U256::from_limbs([1_000_000, 0, 0, 0]);
+
 enum JoinKind {
     Init,
     ExactTokensInForBptOut,
@@ -43,63 +49,119 @@ pub fn decode_in_out_on_join_pool(
     Ok(Some((join_pool_in, join_pool_out)))
 }
 pub fn process_on_join_pool_trace(
+    pool: &PoolConfig,
     state_by_sub_path: &StateBySubPath,
     sub_trace_address: &[usize],
     join_pool_in: onJoinPoolCall,
     join_pool_out: onJoinPoolReturn,
-) -> Result<Option<Swap>> {
+) -> Result<LiquidityEvent> {
     let join_kind: JoinKind = join_pool_in
         .userData
         .get(0..32)
         .ok_or_eyre("JoinKind not found in userData")?
         .try_into()?;
-    if matches!(join_kind, JoinKind::Init) {
-        info!("Skip the join init pool.");
-        return Ok(None);
-    }
+    let sdai_sent = *join_pool_out
+        ._0
+        .get(pool.sdai.index)
+        .ok_or_eyre("sDAI amount sent to the pool not found")?;
+    let eure_sent = *join_pool_out
+        ._0
+        .get(pool.eure.index)
+        .ok_or_eyre("EURe amount sent to the pool not found")?;
 
-    match join_kind {
-        JoinKind::ExactTokensInForBptOut => compute_join_pool_exact_asset_to_bpt(
-            state_by_sub_path,
-            sub_trace_address,
-            &join_pool_in,
-            &join_pool_out,
-        ),
-        JoinKind::TokenInForExactBptOut => Err(eyre!("TokenInForExactBptOut not implemented yet")),
+    let (bpt_received, (sdai_from_bpt, eure_from_bpt), swap) = match join_kind {
+        JoinKind::Init => {
+            info!("Skip the join init pool.");
+            let bpt_received =
+                compute_bpt_received(state_by_sub_path, sub_trace_address, &join_pool_in).or_else(
+                    |error| {
+                        debug!("{:#}, using the BPT supply minted by the init join", error);
+                        compute_init_bpt_minted(pool, state_by_sub_path, sub_trace_address)
+                    },
+                )?;
+            (
+                bpt_received,
+                (sdai_sent, eure_sent),
+                Skippable::Skipped(SkipReason::InitJoin),
+            )
+        }
+        JoinKind::ExactTokensInForBptOut => {
+            let bpt_received =
+                compute_bpt_received(state_by_sub_path, sub_trace_address, &join_pool_in)?;
+            let sdai_eure_from_bpt = compute_sdai_eure_from_join_bpt(
+                pool,
+                state_by_sub_path,
+                sub_trace_address,
+                &join_pool_in,
+                bpt_received,
+                sdai_sent,
+                eure_sent,
+            )?;
+            (
+                bpt_received,
+                sdai_eure_from_bpt,
+                compute_join_pool_exact_asset_to_bpt(sdai_sent, eure_sent, sdai_eure_from_bpt)?,
+            )
+        }
+        JoinKind::TokenInForExactBptOut => {
+            let bpt_received = U256::try_from_be_slice(
+                join_pool_in
+                    .userData
+                    .get(32..64)
+                    .ok_or_eyre("bpt amount out not found in userData")?,
+            )
+            .ok_or_eyre("bpt amount out cant be convert to U256")?;
+            let sdai_eure_from_bpt = compute_sdai_eure_from_join_bpt(
+                pool,
+                state_by_sub_path,
+                sub_trace_address,
+                &join_pool_in,
+                bpt_received,
+                sdai_sent,
+                eure_sent,
+            )?;
+            (
+                bpt_received,
+                sdai_eure_from_bpt,
+                compute_join_pool_one_asset_to_exact_bpt(sdai_sent, eure_sent, sdai_eure_from_bpt)?,
+            )
+        }
         JoinKind::AllTokensInForExactBptOut => {
-            Err(eyre!("AllTokensInForExactBptOut not implemented yet"))
+            debug!("Skip join pool from all token, no swap done");
+            let bpt_received = U256::try_from_be_slice(
+                join_pool_in
+                    .userData
+                    .get(32..64)
+                    .ok_or_eyre("bpt amount out not found in userData")?,
+            )
+            .ok_or_eyre("bpt amount out cant be convert to U256")?;
+            (
+                bpt_received,
+                (sdai_sent, eure_sent),
+                Skippable::Skipped(SkipReason::ProportionalJoin),
+            )
         }
-        JoinKind::Init => Err(eyre!("Init join should already be handled")),
-    }
+    };
+
+    Ok(LiquidityEvent {
+        kind: LiquidityKind::Join,
+        sender: join_pool_in.sender,
+        recipient: join_pool_in.recipient,
+        bpt_amount: bpt_received,
+        sdai_amount: sdai_sent,
+        eure_amount: eure_sent,
+        sdai_proportional_amount: sdai_from_bpt,
+        eure_proportional_amount: eure_from_bpt,
+        swap,
+    })
 }
 
-fn compute_join_pool_exact_asset_to_bpt(
+/// BPT minted to the recipient, from its balance before and after the join
+fn compute_bpt_received(
     state_by_sub_path: &StateBySubPath,
     sub_trace_address: &[usize],
     join_pool_in: &onJoinPoolCall,
-    join_pool_out: &onJoinPoolReturn,
-) -> Result<Option<Swap>> {
-    let is_bpt_mint = true;
-    let sdai_sent = join_pool_out
-        ._0
-        .get(SDAI_ARRAY_INDEX)
-        .ok_or_eyre("sDAI amount sent to the pool not found")?;
-    let eure_sent = join_pool_out
-        ._0
-        .get(EURE_ARRAY_INDEX)
-        .ok_or_eyre("EURe amount sent to the pool not found")?;
-    let sdai_pool_balance = join_pool_in
-        .balances
-        .get(SDAI_ARRAY_INDEX)
-        .ok_or_eyre("sDAI not found in pool balances")?
-        .checked_add(*sdai_sent)
-        .ok_or_eyre("Failed to add sDAI sent to the pool")?;
-    let eure_pool_balance = join_pool_in
-        .balances
-        .get(EURE_ARRAY_INDEX)
-        .ok_or_eyre("EURe not found in pool balances")?
-        .checked_add(*eure_sent)
-        .ok_or_eyre("Failed to add EURe sent to the pool")?;
+) -> Result<U256> {
     let balance_recipient_key = {
         let mut key = B256::left_padding_from(&join_pool_in.recipient.0.0).to_vec();
         key.extend_from_slice(&B256::ZERO.0);
@@ -114,27 +176,118 @@ fn compute_join_pool_exact_asset_to_bpt(
         .get_store_value(&balance_recipient_key, sub_trace_address, &Position::First)
         .ok_or_eyre("BPT owned after not found")?
         .into();
-    let bpt_received = bpt_owned_after
+    bpt_owned_after
         .checked_sub(bpt_owned_before)
-        .ok_or_eyre("BPT owned decreased after a onJoinPool")?;
+        .ok_or_eyre("BPT owned decreased after a onJoinPool")
+}
+
+/// BPT minted to the recipient by the init join, when its balance is not in the trace. The init
+/// mints the whole supply: the BPT preminted to the pool itself, the minimum BPT locked at the
+/// zero address and the rest to the recipient.
+fn compute_init_bpt_minted(
+    pool: &PoolConfig,
+    state_by_sub_path: &StateBySubPath,
+    sub_trace_address: &[usize],
+) -> Result<U256> {
+    let get_store_value = |key, name| {
+        state_by_sub_path
+            .get_store_value(key, sub_trace_address, &Position::Last)
+            .map(|value| U256::from_be_slice(value.split_at(16).1))
+            .ok_or_eyre(format!("{} after the init join not found", name))
+    };
+    let bpt_total_supply = get_store_value(&pool.bpt_total_supply_key, "BPT total supply")?;
+    let bpt_balance_pool = get_store_value(&pool.bpt_balance_pool_key, "BPT balance of the pool")?;
+
+    bpt_total_supply
+        .checked_sub(bpt_balance_pool)
+        .ok_or_eyre("bpt_balance_pool is bigger than bpt_total_supply")?
+        .checked_sub(MINIMUM_BPT)
+        .ok_or_eyre("Init join minted less than the minimum BPT")
+}
 
-    let (sdai_from_bpt, eure_from_bpt) = compute_sdai_eure_from_bpt(
+/// sDAI and EURe owned through the BPT received, at the pool balances after the join
+fn compute_sdai_eure_from_join_bpt(
+    pool: &PoolConfig,
+    state_by_sub_path: &StateBySubPath,
+    sub_trace_address: &[usize],
+    join_pool_in: &onJoinPoolCall,
+    bpt_received: U256,
+    sdai_sent: U256,
+    eure_sent: U256,
+) -> Result<(U256, U256)> {
+    let is_bpt_mint = true;
+    let mut balances = join_pool_in.balances.clone();
+    let sdai_pool_balance = balances
+        .get_mut(pool.sdai.index)
+        .ok_or_eyre("sDAI not found in pool balances")?;
+    *sdai_pool_balance = sdai_pool_balance
+        .checked_add(sdai_sent)
+        .ok_or_eyre("Failed to add sDAI sent to the pool")?;
+    let eure_pool_balance = balances
+        .get_mut(pool.eure.index)
+        .ok_or_eyre("EURe not found in pool balances")?;
+    *eure_pool_balance = eure_pool_balance
+        .checked_add(eure_sent)
+        .ok_or_eyre("Failed to add EURe sent to the pool")?;
+
+    compute_sdai_eure_from_bpt(
+        pool,
         state_by_sub_path,
         sub_trace_address,
         bpt_received,
         is_bpt_mint,
-        &vec![sdai_pool_balance, eure_pool_balance],
+        &balances,
     )
-    .wrap_err("Failed to compute the amount of sdai/eure from bpt ownership")?;
+    .wrap_err("Failed to compute the amount of sdai/eure from bpt ownership")
+}
+
+fn compute_join_pool_one_asset_to_exact_bpt(
+    sdai_sent: U256,
+    eure_sent: U256,
+    (sdai_from_bpt, eure_from_bpt): (U256, U256),
+) -> Result<Skippable<Swap>> {
+    match (sdai_sent, eure_sent) {
+        (sdai_sent, U256::ZERO) => {
+            // Only sDAI sent, the EURe part of the BPT was bought with sDAI
+            let sdai_swapped_to_bpt = sdai_sent.checked_sub(sdai_from_bpt).ok_or_eyre(
+                "sDAI => BPT, but sDAI sent is less then the amount from BPT ownership",
+            )?;
 
-    if sdai_sent > &sdai_from_bpt && eure_sent > &eure_from_bpt {
+            Ok(Skippable::Kept(Swap {
+                is_buy_eure: true,
+                sdai_amount: sdai_swapped_to_bpt.to_string(),
+                eure_amount: eure_from_bpt.to_string(),
+            }))
+        }
+        (U256::ZERO, eure_sent) => {
+            // Only EURe sent, the sDAI part of the BPT was bought with EURe
+            let eure_swapped_to_bpt = eure_sent.checked_sub(eure_from_bpt).ok_or_eyre(
+                "EURe => BPT, but EURe sent is less then the amount from BPT ownership",
+            )?;
+
+            Ok(Skippable::Kept(Swap {
+                is_buy_eure: false,
+                sdai_amount: sdai_from_bpt.to_string(),
+                eure_amount: eure_swapped_to_bpt.to_string(),
+            }))
+        }
+        _ => Err(eyre!("TokenInForExactBptOut join with several assets sent")),
+    }
+}
+
+fn compute_join_pool_exact_asset_to_bpt(
+    sdai_sent: U256,
+    eure_sent: U256,
+    (sdai_from_bpt, eure_from_bpt): (U256, U256),
+) -> Result<Skippable<Swap>> {
+    if sdai_sent > sdai_from_bpt && eure_sent > eure_from_bpt {
         debug!("Skip join pool, no swap done");
-        return Ok(None);
+        return Ok(Skippable::Skipped(SkipReason::NoSwap));
     }
-    match eure_from_bpt.cmp(eure_sent) {
+    match eure_from_bpt.cmp(&eure_sent) {
         std::cmp::Ordering::Equal => {
             debug!("Skip join pool, no swap done");
-            Ok(None)
+            Ok(Skippable::Skipped(SkipReason::NoSwap))
         }
         std::cmp::Ordering::Greater => {
             // Our EURe from BPT is bigger than EURe we sent(so we bought EURe)
@@ -142,10 +295,10 @@ fn compute_join_pool_exact_asset_to_bpt(
                 .checked_sub(sdai_from_bpt)
                 .ok_or_eyre("Buy EURe but our sDAI amount had increase")?;
             let eure_swap = eure_from_bpt
-                .checked_sub(*eure_sent)
+                .checked_sub(eure_sent)
                 .ok_or_eyre("Buy EURe but our EURe amount has decrease\n{:?}")?;
 
-            Ok(Some(Swap {
+            Ok(Skippable::Kept(Swap {
                 is_buy_eure: true,
                 sdai_amount: sdai_swap.to_string(),
                 eure_amount: eure_swap.to_string(),
@@ -154,13 +307,13 @@ fn compute_join_pool_exact_asset_to_bpt(
         std::cmp::Ordering::Less => {
             // Our EURe from BPT is lesser than EURe we sent(so we sold EURe)
             let sdai_swap = sdai_from_bpt
-                .checked_sub(*sdai_sent)
+                .checked_sub(sdai_sent)
                 .ok_or_eyre("Sell EURe but our sDAI amount had decrease")?;
             let eure_swap = eure_sent
                 .checked_sub(eure_from_bpt)
                 .ok_or_eyre("Sell EURe but our sDAI amount had increase")?;
 
-            Ok(Some(Swap {
+            Ok(Skippable::Kept(Swap {
                 is_buy_eure: false,
                 sdai_amount: sdai_swap.to_string(),
                 eure_amount: eure_swap.to_string(),
@@ -168,3 +321,186 @@ fn compute_join_pool_exact_asset_to_bpt(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::swap::test_fixtures::{
+        self, E18, PREMINTED_BPT, bpt_balance_zero_address_key, join_pool,
+    };
+
+    /// 200 BPT virtual supply after the join, on top of the BPT preminted to the pool, and the
+    /// BPT balance of the recipient before and after the join when the trace has it
+    fn state_by_sub_path(pool: &PoolConfig, recipient_bpt: Option<(u128, u128)>) -> StateBySubPath {
+        let mut loads = vec![];
+        let mut stores = vec![
+            (pool.bpt_balance_pool_key, &[][..], PREMINTED_BPT),
+            (pool.bpt_balance_pool_key, &[0][..], PREMINTED_BPT),
+            (
+                pool.bpt_total_supply_key,
+                &[0][..],
+                PREMINTED_BPT + 200 * E18,
+            ),
+        ];
+        if let Some((before, after)) = recipient_bpt {
+            loads.push((bpt_balance_zero_address_key(), &[0][..], before));
+            stores.push((bpt_balance_zero_address_key(), &[0][..], after));
+        }
+        test_fixtures::state_by_sub_path(&loads, &stores)
+    }
+
+    #[test]
+    fn test_token_in_for_exact_bpt_out() -> Result<()> {
+        let pool = PoolConfig::default();
+
+        // 21 sDAI for 20 BPT, 10% of the pool after the join: 12.1 sDAI and 10 EURe
+        let (join_pool_in, join_pool_out) = join_pool(&[2, 20 * E18, 0], [21 * E18, 0]);
+        let Skippable::Kept(swap) = process_on_join_pool_trace(
+            &pool,
+            &state_by_sub_path(&pool, None),
+            &[0],
+            join_pool_in,
+            join_pool_out,
+        )?
+        .swap
+        else {
+            panic!("TokenInForExactBptOut join skipped");
+        };
+        assert!(swap.is_buy_eure);
+        assert_eq!(swap.sdai_amount, (89 * E18 / 10).to_string());
+        assert_eq!(swap.eure_amount, (10 * E18).to_string());
+
+        // 21 EURe for 20 BPT: 10 sDAI and 12.1 EURe
+        let (join_pool_in, join_pool_out) = join_pool(&[2, 20 * E18, 1], [0, 21 * E18]);
+        let Skippable::Kept(swap) = process_on_join_pool_trace(
+            &pool,
+            &state_by_sub_path(&pool, None),
+            &[0],
+            join_pool_in,
+            join_pool_out,
+        )?
+        .swap
+        else {
+            panic!("TokenInForExactBptOut join skipped");
+        };
+        assert!(!swap.is_buy_eure);
+        assert_eq!(swap.sdai_amount, (10 * E18).to_string());
+        assert_eq!(swap.eure_amount, (89 * E18 / 10).to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_all_tokens_in_for_exact_bpt_out() -> Result<()> {
+        let pool = PoolConfig::default();
+        let (join_pool_in, join_pool_out) = join_pool(&[3, 20 * E18, 0], [10 * E18, 10 * E18]);
+
+        let liquidity_event = process_on_join_pool_trace(
+            &pool,
+            &state_by_sub_path(&pool, None),
+            &[0],
+            join_pool_in,
+            join_pool_out,
+        )?;
+        assert_eq!(liquidity_event.kind, LiquidityKind::Join);
+        assert_eq!(liquidity_event.bpt_amount, U256::from(20 * E18));
+        assert_eq!(liquidity_event.sdai_amount, U256::from(10 * E18));
+        assert_eq!(
+            liquidity_event.eure_proportional_amount,
+            U256::from(10 * E18)
+        );
+        assert!(matches!(
+            liquidity_event.swap,
+            Skippable::Skipped(SkipReason::ProportionalJoin)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exact_tokens_in_for_bpt_out() -> Result<()> {
+        let pool = PoolConfig::default();
+
+        // 12 sDAI and 8 EURe for 20 BPT, 10% of the pool after the join: 11.2 sDAI and
+        // 10.8 EURe, 0.8 sDAI bought 2.8 EURe
+        let (join_pool_in, join_pool_out) = join_pool(&[1, 0, 0], [12 * E18, 8 * E18]);
+        let liquidity_event = process_on_join_pool_trace(
+            &pool,
+            &state_by_sub_path(&pool, Some((30 * E18, 50 * E18))),
+            &[0],
+            join_pool_in,
+            join_pool_out,
+        )?;
+        assert_eq!(liquidity_event.kind, LiquidityKind::Join);
+        assert_eq!(liquidity_event.bpt_amount, U256::from(20 * E18));
+        assert_eq!(
+            liquidity_event.sdai_proportional_amount,
+            U256::from(112 * E18 / 10)
+        );
+        assert_eq!(
+            liquidity_event.eure_proportional_amount,
+            U256::from(108 * E18 / 10)
+        );
+        let Skippable::Kept(swap) = liquidity_event.swap else {
+            panic!("ExactTokensInForBptOut join skipped");
+        };
+        assert!(swap.is_buy_eure);
+        assert_eq!(swap.sdai_amount, (8 * E18 / 10).to_string());
+        assert_eq!(swap.eure_amount, (28 * E18 / 10).to_string());
+
+        // Less BPT owned after the join
+        let (join_pool_in, join_pool_out) = join_pool(&[1, 0, 0], [12 * E18, 8 * E18]);
+        assert!(
+            process_on_join_pool_trace(
+                &pool,
+                &state_by_sub_path(&pool, Some((50 * E18, 30 * E18))),
+                &[0],
+                join_pool_in,
+                join_pool_out,
+            )
+            .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_init() -> Result<()> {
+        let pool = PoolConfig::default();
+
+        // The recipient balance is not in the trace, it got the whole supply minted by the init
+        // but the premint and the minimum BPT
+        let (join_pool_in, join_pool_out) = join_pool(&[0, 0, 0], [100 * E18, 100 * E18]);
+        let liquidity_event = process_on_join_pool_trace(
+            &pool,
+            &state_by_sub_path(&pool, None),
+            &[0],
+            join_pool_in,
+            join_pool_out,
+        )?;
+        assert_eq!(
+            liquidity_event.bpt_amount,
+            U256::from(200 * E18) - MINIMUM_BPT
+        );
+        assert_eq!(
+            liquidity_event.sdai_proportional_amount,
+            U256::from(100 * E18)
+        );
+        assert!(matches!(
+            liquidity_event.swap,
+            Skippable::Skipped(SkipReason::InitJoin)
+        ));
+
+        let (join_pool_in, join_pool_out) = join_pool(&[0, 0, 0], [100 * E18, 100 * E18]);
+        let liquidity_event = process_on_join_pool_trace(
+            &pool,
+            &state_by_sub_path(&pool, Some((0, 199 * E18))),
+            &[0],
+            join_pool_in,
+            join_pool_out,
+        )?;
+        assert_eq!(liquidity_event.bpt_amount, U256::from(199 * E18));
+
+        Ok(())
+    }
+}
@@ -1,7 +1,6 @@
-use crate::download::swap::{
-    BALANCER_SDAI_EURE_POOL_ADDRESS, EURE_ADDRESS, EURE_ARRAY_INDEX, SDAI_ADDRESS,
-    SDAI_ARRAY_INDEX, Swap, compute_sdai_eure_from_bpt,
-};
+use crate::download::pool::{PoolConfig, PoolToken};
+use crate::download::skipped::Skippable;
+use crate::download::swap::{Swap, compute_sdai_eure_from_bpt};
 use crate::helper::StateBySubPath;
 use alloy::primitives::U256;
 use alloy::rpc::types::trace::parity::{CallAction, TraceOutput};
@@ -44,41 +43,63 @@ pub fn decode_in_out_on_swap(
 }
 
 pub fn process_on_swap_trace(
+    pool: &PoolConfig,
     state_by_sub_path: &StateBySubPath,
     sub_trace_address: &[usize],
     swap_in: onSwapCall,
     swap_out: U256,
-) -> Result<Option<Swap>> {
-    match (swap_in.swapRequest.tokenIn, swap_in.swapRequest.tokenOut) {
-        (SDAI_ADDRESS, EURE_ADDRESS) => {
-            return Ok(Some(compute_swap_csv_sdai_to_eure(&swap_in, swap_out)));
+) -> Result<Skippable<Swap>> {
+    let token_in = pool.token(swap_in.swapRequest.tokenIn);
+    let token_out = pool.token(swap_in.swapRequest.tokenOut);
+
+    match (token_in, token_out) {
+        (Some(PoolToken::Sdai), Some(PoolToken::Eure)) => {
+            return Ok(Skippable::Kept(compute_swap_csv_sdai_to_eure(
+                &swap_in, swap_out,
+            )));
         }
-        (EURE_ADDRESS, SDAI_ADDRESS) => {
-            return Ok(Some(compute_swap_csv_eure_to_sdai(&swap_in, swap_out)));
+        (Some(PoolToken::Eure), Some(PoolToken::Sdai)) => {
+            return Ok(Skippable::Kept(compute_swap_csv_eure_to_sdai(
+                &swap_in, swap_out,
+            )));
         }
         _ => {}
     }
 
-    match (swap_in.swapRequest.tokenIn, swap_in.swapRequest.tokenOut) {
-        (BALANCER_SDAI_EURE_POOL_ADDRESS, EURE_ADDRESS) => {
-            compute_swap_csv_bpt_to_eure(&state_by_sub_path, sub_trace_address, &swap_in, swap_out)
-                .map(Some)
-        }
-        (BALANCER_SDAI_EURE_POOL_ADDRESS, SDAI_ADDRESS) => {
-            compute_swap_csv_bpt_to_sdai(&state_by_sub_path, sub_trace_address, &swap_in, swap_out)
-                .map(Some)
-        }
-        (EURE_ADDRESS, BALANCER_SDAI_EURE_POOL_ADDRESS) => {
-            compute_swap_csv_eure_to_bpt(&state_by_sub_path, sub_trace_address, &swap_in, swap_out)
-                .map(Some)
-        }
-        (SDAI_ADDRESS, BALANCER_SDAI_EURE_POOL_ADDRESS) => {
-            compute_swap_csv_sdai_to_bpt(&state_by_sub_path, sub_trace_address, &swap_in, swap_out)
-                .map(Some)
-        }
-        (SDAI_ADDRESS, SDAI_ADDRESS)
-        | (EURE_ADDRESS, EURE_ADDRESS)
-        | (BALANCER_SDAI_EURE_POOL_ADDRESS, BALANCER_SDAI_EURE_POOL_ADDRESS) => {
+    match (token_in, token_out) {
+        (Some(PoolToken::Bpt), Some(PoolToken::Eure)) => compute_swap_csv_bpt_to_eure(
+            pool,
+            state_by_sub_path,
+            sub_trace_address,
+            &swap_in,
+            swap_out,
+        )
+        .map(Skippable::Kept),
+        (Some(PoolToken::Bpt), Some(PoolToken::Sdai)) => compute_swap_csv_bpt_to_sdai(
+            pool,
+            state_by_sub_path,
+            sub_trace_address,
+            &swap_in,
+            swap_out,
+        )
+        .map(Skippable::Kept),
+        (Some(PoolToken::Eure), Some(PoolToken::Bpt)) => compute_swap_csv_eure_to_bpt(
+            pool,
+            state_by_sub_path,
+            sub_trace_address,
+            &swap_in,
+            swap_out,
+        )
+        .map(Skippable::Kept),
+        (Some(PoolToken::Sdai), Some(PoolToken::Bpt)) => compute_swap_csv_sdai_to_bpt(
+            pool,
+            state_by_sub_path,
+            sub_trace_address,
+            &swap_in,
+            swap_out,
+        )
+        .map(Skippable::Kept),
+        (Some(token_in), Some(token_out)) if token_in == token_out => {
             Err(eyre!("onSwap same in and out"))
         }
         _ => Err(eyre::eyre!("onSwap unknown token")),
@@ -99,6 +120,7 @@ fn compute_swap_csv_eure_to_sdai(swap_in: &onSwapCall, sdai_received: U256) -> S
     }
 }
 fn compute_swap_csv_bpt_to_sdai(
+    pool: &PoolConfig,
     state_by_sub_path: &StateBySubPath,
     sub_trace_address: &[usize],
     swap_in: &onSwapCall,
@@ -106,6 +128,7 @@ fn compute_swap_csv_bpt_to_sdai(
 ) -> Result<Swap> {
     let is_bpt_mint = false;
     let (sdai_from_bpt, eure_from_bpt) = compute_sdai_eure_from_bpt(
+        pool,
         state_by_sub_path,
         sub_trace_address,
         swap_in.swapRequest.amount,
@@ -125,6 +148,7 @@ fn compute_swap_csv_bpt_to_sdai(
     })
 }
 fn compute_swap_csv_bpt_to_eure(
+    pool: &PoolConfig,
     state_by_sub_path: &StateBySubPath,
     sub_trace_address: &[usize],
     swap_in: &onSwapCall,
@@ -132,6 +156,7 @@ fn compute_swap_csv_bpt_to_eure(
 ) -> Result<Swap> {
     let is_bpt_mint = false;
     let (sdai_from_bpt, eure_from_bpt) = compute_sdai_eure_from_bpt(
+        pool,
         state_by_sub_path,
         sub_trace_address,
         swap_in.swapRequest.amount,
@@ -151,6 +176,7 @@ fn compute_swap_csv_bpt_to_eure(
     })
 }
 fn compute_swap_csv_sdai_to_bpt(
+    pool: &PoolConfig,
     state_by_sub_path: &StateBySubPath,
     sub_trace_address: &[usize],
     swap_in: &onSwapCall,
@@ -160,12 +186,13 @@ fn compute_swap_csv_sdai_to_bpt(
 
     let mut balances = swap_in.balances.clone();
     balances
-        .get_mut(SDAI_ARRAY_INDEX)
+        .get_mut(pool.sdai.index)
         .ok_or_eyre("sDAI balance of the pool not found")?
         .checked_add(swap_in.swapRequest.amount)
         .ok_or_eyre("sDAI balance of the pool + sDAI swap amount overflow")?;
 
     let (sdai_from_bpt, eure_from_bpt) = compute_sdai_eure_from_bpt(
+        pool,
         state_by_sub_path,
         sub_trace_address,
         bpt_received,
@@ -189,6 +216,7 @@ fn compute_swap_csv_sdai_to_bpt(
     })
 }
 fn compute_swap_csv_eure_to_bpt(
+    pool: &PoolConfig,
     state_by_sub_path: &StateBySubPath,
     sub_trace_address: &[usize],
     swap_in: &onSwapCall,
@@ -198,12 +226,13 @@ fn compute_swap_csv_eure_to_bpt(
 
     let mut balances = swap_in.balances.clone();
     balances
-        .get_mut(EURE_ARRAY_INDEX)
+        .get_mut(pool.eure.index)
         .ok_or_eyre("EURe balance of the pool not found")?
         .checked_add(swap_in.swapRequest.amount)
         .ok_or_eyre("EURe balance of the pool + EURe swap amount overflow")?;
 
     let (sdai_from_bpt, eure_from_bpt) = compute_sdai_eure_from_bpt(
+        pool,
         state_by_sub_path,
         sub_trace_address,
         bpt_received,
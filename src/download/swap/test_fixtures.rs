@@ -0,0 +1,295 @@
+//! Fixtures shared by the swap tests: pool calls and their storage for the join and exit pool
+//! tests, where the zero address is the sender and the recipient, the pool holds 100 sDAI and
+//! 100 EURe, the pool call is at trace address [0], and a cached swap for the offline
+//! `SwapFetcher` tests
+use crate::download::block_timestamp::BlockTimestampFetcher;
+use crate::download::pool::PoolConfig;
+use crate::download::swap::SwapFetcher;
+use crate::download::swap::on_exit_pool::{onExitPoolCall, onExitPoolReturn};
+use crate::download::swap::on_join_pool::{onJoinPoolCall, onJoinPoolReturn};
+use crate::download::swap::on_swap::{SwapKind, SwapRequest, onSwapCall};
+use crate::download::test_helper::temp_data_paths;
+use crate::download::trace_backend::TraceBackend;
+use crate::download::trace_cache::TraceCache;
+use crate::helper::{StateBySubPath, write_json_gz};
+use crate::paths::DataPaths;
+use alloy::primitives::{Address, B256, BlockNumber, Bytes, TxHash, U256, keccak256};
+use alloy::rpc::types::trace::parity::{
+    Action, CallAction, CallOutput, CallType, LocalizedTransactionTrace, TraceOutput,
+    TransactionTrace, VmExecutedOperation, VmInstruction, VmTrace,
+};
+use alloy::sol_types::SolCall;
+use eyre::Result;
+use std::collections::{BTreeMap, HashMap};
+
+pub const E18: u128 = 1_000_000_000_000_000_000;
+
+/// BPT preminted to the pool itself, as a composable stable pool does on init
+pub const PREMINTED_BPT: u128 = 1 << 111;
+
+/// Storage key of the BPT balance of the zero address
+pub fn bpt_balance_zero_address_key() -> B256 {
+    let mut key = B256::left_padding_from(&Address::ZERO.0.0).to_vec();
+    key.extend_from_slice(&B256::ZERO.0);
+    keccak256(key)
+}
+
+/// Storage values loaded and stored by the pool call, as (key, sub path, value)
+pub fn state_by_sub_path(
+    loads: &[(B256, &[usize], u128)],
+    stores: &[(B256, &[usize], u128)],
+) -> StateBySubPath {
+    let map = |values: &[(B256, &[usize], u128)]| {
+        let mut map: HashMap<B256, BTreeMap<Vec<usize>, Vec<B256>>> = HashMap::new();
+        for (key, sub_path, value) in values {
+            map.entry(*key)
+                .or_default()
+                .insert(sub_path.to_vec(), vec![B256::from(U256::from(*value))]);
+        }
+        map
+    };
+    StateBySubPath {
+        load_map: map(loads),
+        store_map: map(stores),
+    }
+}
+
+pub fn join_pool(user_data: &[u128], amounts_in: [u128; 2]) -> (onJoinPoolCall, onJoinPoolReturn) {
+    let join_pool_in = onJoinPoolCall {
+        poolId: B256::ZERO,
+        sender: Address::ZERO,
+        recipient: Address::ZERO,
+        balances: pool_balances(),
+        lastChangeBlock: U256::ZERO,
+        protocolSwapFeePercentage: U256::ZERO,
+        userData: encode_words(user_data),
+    };
+    let join_pool_out = onJoinPoolReturn {
+        _0: amounts(&amounts_in),
+        _1: vec![U256::ZERO, U256::ZERO],
+    };
+    (join_pool_in, join_pool_out)
+}
+
+pub fn exit_pool(user_data: &[u128], amounts_out: [u128; 2]) -> (onExitPoolCall, onExitPoolReturn) {
+    let exit_pool_in = onExitPoolCall {
+        poolId: B256::ZERO,
+        sender: Address::ZERO,
+        recipient: Address::ZERO,
+        balances: pool_balances(),
+        lastChangeBlock: U256::ZERO,
+        protocolSwapFeePercentage: U256::ZERO,
+        userData: encode_words(user_data),
+    };
+    let exit_pool_out = onExitPoolReturn {
+        _0: amounts(&amounts_out),
+        _1: vec![U256::ZERO, U256::ZERO],
+    };
+    (exit_pool_in, exit_pool_out)
+}
+
+fn pool_balances() -> Vec<U256> {
+    amounts(&[100 * E18, 100 * E18])
+}
+
+fn amounts(amounts: &[u128]) -> Vec<U256> {
+    amounts.iter().map(|amount| U256::from(*amount)).collect()
+}
+
+fn encode_words(words: &[u128]) -> Bytes {
+    Bytes::from(
+        words
+            .iter()
+            .flat_map(|word| B256::from(U256::from(*word)).0)
+            .collect::<Vec<u8>>(),
+    )
+}
+
+pub const BLOCK_NUMBER: BlockNumber = 30_000_000;
+pub const BLOCK_TIMESTAMP: u64 = 1_700_000_100;
+pub const SENDER: Address = Address::repeat_byte(0x11);
+pub const RECIPIENT: Address = Address::repeat_byte(0x22);
+pub const TX_FROM: Address = Address::repeat_byte(0x33);
+
+/// A data dir with blocks.csv knowing BLOCK_NUMBER, for the offline tests
+pub fn offline_data_paths(name: &str) -> Result<DataPaths> {
+    let data_paths = temp_data_paths(&format!("swap-{name}"))?;
+    std::fs::write(
+        data_paths.blocks_csv(),
+        format!("timestamp,number,hash\n{BLOCK_TIMESTAMP},{BLOCK_NUMBER},\n"),
+    )?;
+    Ok(data_paths)
+}
+
+/// Rate cache storage value: last update, duration, old and new rates
+fn price_cache_value(last_update: u32, price: u128) -> U256 {
+    let mut value = [0u8; 32];
+    value[0..4].copy_from_slice(&last_update.to_be_bytes());
+    value[4..8].copy_from_slice(&3600u32.to_be_bytes());
+    value[8..20].copy_from_slice(&price.to_be_bytes()[4..]);
+    value[20..32].copy_from_slice(&price.to_be_bytes()[4..]);
+    U256::from_be_bytes(value)
+}
+
+/// Cache an onSwap selling `sdai_amount` sDAI for `eure_amount` EURe, at trace address [0]
+/// of the transaction, with its receipt, transaction and vm trace. The pool call loads both
+/// rate caches unless `with_price_cache` is false, which fails its decoding.
+pub fn cache_sdai_to_eure_swap(
+    data_paths: &DataPaths,
+    tx_hash: TxHash,
+    sdai_amount: u128,
+    eure_amount: u128,
+    with_price_cache: bool,
+) -> Result<LocalizedTransactionTrace> {
+    let pool = PoolConfig::default();
+    let trace_cache_dir = data_paths.trace_cache_dir();
+    let block_hash = B256::repeat_byte(0xbb);
+
+    let localized_trace = LocalizedTransactionTrace {
+        trace: TransactionTrace {
+            action: Action::Call(CallAction {
+                from: pool.vault_address,
+                call_type: CallType::Call,
+                gas: 0,
+                input: onSwapCall {
+                    swapRequest: SwapRequest {
+                        kind: SwapKind::GIVEN_IN,
+                        tokenIn: pool.sdai.address,
+                        tokenOut: pool.eure.address,
+                        amount: U256::from(sdai_amount),
+                        poolId: B256::ZERO,
+                        lastChangeBlock: U256::ZERO,
+                        from: SENDER,
+                        to: RECIPIENT,
+                        userData: Default::default(),
+                    },
+                    balances: vec![U256::from(100 * E18), U256::from(100 * E18)],
+                    indexIn: U256::from(pool.sdai.index),
+                    indexOut: U256::from(pool.eure.index),
+                }
+                .abi_encode()
+                .into(),
+                to: pool.pool_address,
+                value: U256::ZERO,
+            }),
+            error: None,
+            result: Some(TraceOutput::Call(CallOutput {
+                gas_used: 0,
+                output: B256::from(U256::from(eure_amount)).to_vec().into(),
+            })),
+            subtraces: 0,
+            trace_address: vec![0],
+        },
+        block_hash: Some(block_hash),
+        block_number: Some(BLOCK_NUMBER),
+        transaction_hash: Some(tx_hash),
+        transaction_position: Some(3),
+    };
+
+    // PUSH <key> then SLOAD <value>, for each rate cache
+    const SLOAD_OPCODE: u8 = 0x54;
+    let executed = |push: U256| {
+        Some(VmExecutedOperation {
+            used: 0,
+            push: vec![push],
+            mem: None,
+            store: None,
+        })
+    };
+    let instruction = |pc: usize, push: U256| VmInstruction {
+        cost: 0,
+        ex: executed(push),
+        pc,
+        sub: None,
+        op: None,
+        idx: None,
+    };
+    let pool_ops = if with_price_cache {
+        vec![
+            instruction(0, pool.sdai.price_cache_key.into()),
+            instruction(1, price_cache_value(1_700_000_000, 11 * E18 / 10)),
+            instruction(2, pool.eure.price_cache_key.into()),
+            instruction(3, price_cache_value(1_690_000_000, E18)),
+        ]
+    } else {
+        vec![instruction(0, U256::ZERO)]
+    };
+    let vm_trace = VmTrace {
+        code: Default::default(),
+        ops: vec![VmInstruction {
+            cost: 0,
+            ex: None,
+            pc: 0,
+            sub: Some(VmTrace {
+                code: vec![0, SLOAD_OPCODE, 0, SLOAD_OPCODE].into(),
+                ops: pool_ops,
+            }),
+            op: None,
+            idx: None,
+        }],
+    };
+    write_json_gz(
+        &trace_cache_dir.join(format!("{tx_hash}.vm.parity.json.gz")),
+        &vm_trace,
+    )?;
+
+    write_json_gz(
+        &trace_cache_dir.join(format!("{tx_hash}.receipt.json.gz")),
+        &serde_json::json!({
+            "type": "0x2",
+            "status": "0x1",
+            "cumulativeGasUsed": "0x0",
+            "logs": [],
+            "logsBloom": format!("0x{}", "0".repeat(512)),
+            "transactionHash": tx_hash,
+            "transactionIndex": "0x3",
+            "blockHash": block_hash,
+            "blockNumber": format!("{BLOCK_NUMBER:#x}"),
+            "gasUsed": "0x0",
+            "effectiveGasPrice": "0x0",
+            "from": TX_FROM,
+            "to": pool.vault_address,
+            "contractAddress": null,
+        }),
+    )?;
+    write_json_gz(
+        &trace_cache_dir.join(format!("{tx_hash}.tx.json.gz")),
+        &serde_json::json!({
+            "type": "0x2",
+            "chainId": "0x64",
+            "nonce": "0x7",
+            "gas": "0x0",
+            "maxFeePerGas": "0x0",
+            "maxPriorityFeePerGas": "0x0",
+            "to": pool.vault_address,
+            "value": "0x0",
+            "accessList": [],
+            "input": "0x",
+            "r": "0x1",
+            "s": "0x1",
+            "yParity": "0x0",
+            "v": "0x0",
+            "hash": tx_hash,
+            "blockHash": block_hash,
+            "blockNumber": format!("{BLOCK_NUMBER:#x}"),
+            "transactionIndex": "0x3",
+            "from": TX_FROM,
+            "gasPrice": "0x0",
+        }),
+    )?;
+
+    Ok(localized_trace)
+}
+
+pub fn offline_swap_fetcher(data_paths: &DataPaths) -> Result<SwapFetcher> {
+    let block_timestamp_fetcher = BlockTimestampFetcher::try_new(None, data_paths)?;
+    let trace_cache =
+        TraceCache::try_new(None, TraceBackend::default(), data_paths.trace_cache_dir())?;
+    SwapFetcher::try_new(
+        trace_cache,
+        PoolConfig::default(),
+        block_timestamp_fetcher,
+        data_paths,
+        1,
+    )
+}
@@ -0,0 +1,22 @@
+//! Setup shared by the tests reading and writing a data dir
+use crate::paths::DataPaths;
+use clap::Parser;
+use eyre::Result;
+
+#[derive(Parser)]
+struct TestArgs {
+    #[command(flatten)]
+    data_paths: DataPaths,
+}
+
+/// An empty data dir under the temp dir, left over files of a previous run removed, with the
+/// default paths of `--data-dir`
+pub fn temp_data_paths(name: &str) -> Result<DataPaths> {
+    let data_dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    if data_dir.exists() {
+        std::fs::remove_dir_all(&data_dir)?;
+    }
+    std::fs::create_dir_all(&data_dir)?;
+
+    Ok(TestArgs::parse_from(["test", "--data-dir", data_dir.to_str().unwrap()]).data_paths)
+}
@@ -0,0 +1,256 @@
+use super::ProviderFiller;
+use crate::helper::fetch_sub_vm_trace;
+use alloy::primitives::{Bytes, TxHash};
+use alloy::providers::ext::DebugApi;
+use alloy::rpc::types::trace::geth::{
+    GethDebugTracingOptions, GethDefaultTracingOptions, StructLog,
+};
+use alloy::rpc::types::trace::parity::{StorageDelta, VmExecutedOperation, VmInstruction, VmTrace};
+use eyre::{Result, WrapErr, bail};
+
+const SLOAD_OPCODE: u8 = 0x54;
+const SSTORE_OPCODE: u8 = 0x55;
+const CALL_OPS: [&str; 6] = [
+    "CALL",
+    "CALLCODE",
+    "DELEGATECALL",
+    "STATICCALL",
+    "CREATE",
+    "CREATE2",
+];
+const NON_PUSHING_OPS: [&str; 23] = [
+    "STOP",
+    "POP",
+    "MSTORE",
+    "MSTORE8",
+    "SSTORE",
+    "TSTORE",
+    "JUMP",
+    "JUMPI",
+    "JUMPDEST",
+    "CALLDATACOPY",
+    "CODECOPY",
+    "EXTCODECOPY",
+    "RETURNDATACOPY",
+    "MCOPY",
+    "LOG0",
+    "LOG1",
+    "LOG2",
+    "LOG3",
+    "LOG4",
+    "RETURN",
+    "REVERT",
+    "INVALID",
+    "SELFDESTRUCT",
+];
+
+/// Rpc method used to replay a transaction opcode by opcode
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default)]
+pub enum TraceBackend {
+    /// Parity `trace_replayTransaction` vmTrace (OpenEthereum, Nethermind, Erigon)
+    #[default]
+    Parity,
+    /// Geth `debug_traceTransaction` struct logs (Geth, Nethermind, Erigon)
+    Geth,
+}
+
+impl TraceBackend {
+    pub fn name(self) -> &'static str {
+        match self {
+            TraceBackend::Parity => "parity",
+            TraceBackend::Geth => "geth",
+        }
+    }
+
+    /// Full vm trace of the transaction, the struct logs of geth are converted to a parity vm trace
+    pub async fn fetch_vm_trace(
+        self,
+        provider: &ProviderFiller,
+        tx_hash: TxHash,
+    ) -> Result<VmTrace> {
+        match self {
+            TraceBackend::Parity => fetch_sub_vm_trace(provider, tx_hash, &[]).await,
+            TraceBackend::Geth => {
+                let tracing_options = GethDebugTracingOptions {
+                    config: GethDefaultTracingOptions::default()
+                        .disable_memory()
+                        .disable_storage()
+                        .disable_return_data(),
+                    ..Default::default()
+                };
+                let default_frame = provider
+                    .debug_trace_transaction(tx_hash, tracing_options)
+                    .await?
+                    .try_into_default_frame()
+                    .wrap_err(format!("Unexpected debug_traceTransaction of {tx_hash}"))?;
+
+                vm_trace_from_struct_logs(&default_frame.struct_logs)
+            }
+        }
+    }
+}
+
+/// Rebuild the parity vm trace tree from the geth struct logs. Only what `StateBySubPath` and
+/// `extract_sub_vm_trace` read is filled:
+/// - `push` is the top of the stack at the next opcode of the same call, skipped for the opcodes
+///   that never push
+/// - `store` is the key/value of SSTORE
+/// - `sub` is set on every call, with no opcode when the callee has no code
+/// - `code` only holds the SLOAD/SSTORE opcodes at their pc, `op` holds every opcode name
+pub fn vm_trace_from_struct_logs(struct_logs: &[StructLog]) -> Result<VmTrace> {
+    let Some(first_struct_log) = struct_logs.first() else {
+        return Ok(VmTrace {
+            code: Bytes::new(),
+            ops: Vec::new(),
+        });
+    };
+
+    let mut position = 0;
+    let vm_trace = build_vm_trace(struct_logs, &mut position, first_struct_log.depth)?;
+    if position != struct_logs.len() {
+        bail!(
+            "Struct logs left after the root call ({}/{})",
+            position,
+            struct_logs.len()
+        );
+    }
+
+    Ok(vm_trace)
+}
+
+fn build_vm_trace(struct_logs: &[StructLog], position: &mut usize, depth: u64) -> Result<VmTrace> {
+    let mut code = Vec::new();
+    let mut ops = Vec::new();
+
+    while let Some(struct_log) = struct_logs.get(*position) {
+        if struct_log.depth < depth {
+            break;
+        }
+        if struct_log.depth > depth {
+            bail!(
+                "Struct log {} at depth {} is not inside a call",
+                *position,
+                struct_log.depth
+            );
+        }
+        *position += 1;
+
+        let op = struct_log.op.as_str();
+        let stack = struct_log.stack.as_deref().unwrap_or_default();
+        let pc = usize::try_from(struct_log.pc)?;
+
+        let sub = match CALL_OPS.contains(&op) {
+            true => match struct_logs.get(*position) {
+                Some(next_struct_log) if next_struct_log.depth > depth => {
+                    Some(build_vm_trace(struct_logs, position, depth + 1)?)
+                }
+                _ => Some(VmTrace {
+                    code: Bytes::new(),
+                    ops: Vec::new(),
+                }),
+            },
+            false => None,
+        };
+
+        let push = match NON_PUSHING_OPS.contains(&op) {
+            true => Vec::new(),
+            false => struct_logs
+                .get(*position)
+                .filter(|next_struct_log| next_struct_log.depth == depth)
+                .and_then(|next_struct_log| next_struct_log.stack.as_ref()?.last().copied())
+                .into_iter()
+                .collect(),
+        };
+
+        let store = match op {
+            "SSTORE" => match stack {
+                [.., val, key] => Some(StorageDelta {
+                    key: *key,
+                    val: *val,
+                }),
+                _ => bail!("SSTORE at pc {} without key and value on the stack", pc),
+            },
+            _ => None,
+        };
+
+        let opcode = match op {
+            "SLOAD" => Some(SLOAD_OPCODE),
+            "SSTORE" => Some(SSTORE_OPCODE),
+            _ => None,
+        };
+        if let Some(opcode) = opcode {
+            if code.len() <= pc {
+                code.resize(pc + 1, 0);
+            }
+            code[pc] = opcode;
+        }
+
+        ops.push(VmInstruction {
+            pc,
+            cost: struct_log.gas_cost,
+            ex: Some(VmExecutedOperation {
+                used: struct_log.gas.saturating_sub(struct_log.gas_cost),
+                push,
+                mem: None,
+                store,
+            }),
+            sub,
+            op: Some(struct_log.op.clone()),
+            idx: None,
+        });
+    }
+
+    Ok(VmTrace {
+        code: code.into(),
+        ops,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::{Position, StateBySubPath};
+    use alloy::primitives::{B256, U256};
+
+    fn struct_log(pc: u64, op: &str, depth: u64, stack: &[u64]) -> StructLog {
+        StructLog {
+            pc,
+            op: op.to_string(),
+            depth,
+            stack: Some(stack.iter().map(|value| U256::from(*value)).collect()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_vm_trace_from_struct_logs() {
+        let struct_logs = vec![
+            struct_log(0, "PUSH1", 1, &[]),
+            struct_log(2, "SLOAD", 1, &[7]),
+            struct_log(3, "STATICCALL", 1, &[0, 0, 0, 0, 1, 42]),
+            struct_log(4, "CALL", 1, &[42, 0, 0, 0, 0, 0, 2, 100]),
+            struct_log(0, "PUSH1", 2, &[]),
+            struct_log(2, "PUSH1", 2, &[9]),
+            struct_log(4, "SSTORE", 2, &[9, 8]),
+            struct_log(5, "STOP", 2, &[]),
+            struct_log(5, "STOP", 1, &[1, 1]),
+        ];
+
+        let vm_trace = vm_trace_from_struct_logs(&struct_logs).unwrap();
+        assert_eq!(vm_trace.ops.len(), 5);
+        assert!(vm_trace.ops[2].sub.as_ref().unwrap().ops.is_empty());
+        assert_eq!(vm_trace.ops[3].sub.as_ref().unwrap().ops.len(), 4);
+
+        let state_by_sub_path = StateBySubPath::new(&vm_trace);
+        let key = B256::from(U256::from(7));
+        assert_eq!(
+            state_by_sub_path.get_load_value(&key, &[], &Position::First),
+            Some(B256::from(U256::from(42)))
+        );
+        let key = B256::from(U256::from(8));
+        assert_eq!(
+            state_by_sub_path.get_store_value(&key, &[0], &Position::First),
+            Some(B256::from(U256::from(9)))
+        );
+    }
+}
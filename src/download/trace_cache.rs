@@ -0,0 +1,362 @@
+use super::ProviderFiller;
+use crate::download::discovery::sort_localized_traces;
+use crate::download::trace_backend::TraceBackend;
+use crate::helper::{read_json_gz, write_json_gz};
+use alloy::primitives::{Address, BlockNumber, TxHash};
+use alloy::providers::Provider;
+use alloy::providers::ext::TraceApi;
+use alloy::rpc::types::trace::parity::{LocalizedTransactionTrace, VmTrace};
+use alloy::rpc::types::{Transaction, TransactionReceipt};
+use eyre::{OptionExt, Result, WrapErr, eyre};
+use log::{debug, info, warn};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Gzipped JSON of what the node returned for a transaction, reused across runs:
+/// - `<tx_hash>.vm.<backend>.json.gz` the replayed vm trace, per backend as the geth struct logs
+///   only convert to a part of the parity vm trace
+/// - `<tx_hash>.receipt.json.gz` the receipt
+/// - `<tx_hash>.tx.json.gz` the transaction, for its nonce, never fetched offline
+/// - `<pool_address>/<tx_hash>.traces.json.gz` the localized traces calling the pool
+///
+/// A transaction is fetched once even when several of its pool calls are fetched concurrently.
+/// Without a provider (offline) a missing file is an error.
+pub struct TraceCache {
+    provider: Option<ProviderFiller>,
+    trace_backend: TraceBackend,
+    cache_dir: PathBuf,
+    fetch_lock_by_tx_hash: Mutex<HashMap<TxHash, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl TraceCache {
+    pub fn try_new(
+        provider: Option<ProviderFiller>,
+        trace_backend: TraceBackend,
+        cache_dir: PathBuf,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(&cache_dir)
+            .wrap_err(format!("Failed to create the trace cache {:?}", cache_dir))?;
+
+        Ok(Self {
+            provider,
+            trace_backend,
+            cache_dir,
+            fetch_lock_by_tx_hash: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Full vm trace of the transaction, read from the cache or replayed then cached
+    pub async fn fetch_vm_trace(&self, tx_hash: TxHash) -> Result<VmTrace> {
+        let vm_trace_file = self.vm_trace_file(&tx_hash, self.trace_backend);
+
+        self.fetch_cached(tx_hash, &vm_trace_file, async || {
+            self.trace_backend
+                .fetch_vm_trace(self.provider()?, tx_hash)
+                .await
+        })
+        .await
+    }
+
+    pub async fn fetch_receipt(&self, tx_hash: TxHash) -> Result<TransactionReceipt> {
+        let receipt_file = self.receipt_file(&tx_hash);
+
+        self.fetch_cached(tx_hash, &receipt_file, async || {
+            self.provider()?
+                .get_transaction_receipt(tx_hash)
+                .await?
+                .ok_or_eyre(format!("Failed to get receipt by hash {tx_hash}"))
+        })
+        .await
+    }
+
+    /// None offline when the transaction is not cached, it only adds the nonce to the swap
+    pub async fn fetch_transaction(&self, tx_hash: TxHash) -> Result<Option<Transaction>> {
+        let transaction_file = self.transaction_file(&tx_hash);
+        if self.provider.is_none() && !transaction_file.exists() {
+            debug!("Transaction {} not cached (offline), no nonce", tx_hash);
+            return Ok(None);
+        }
+
+        self.fetch_cached(tx_hash, &transaction_file, async || {
+            self.provider()?
+                .get_transaction_by_hash(tx_hash)
+                .await?
+                .ok_or_eyre(format!("Failed to get transaction by hash {tx_hash}"))
+        })
+        .await
+        .map(Some)
+    }
+
+    /// Localized traces of the transaction calling the pool, in the node order
+    pub async fn fetch_pool_traces(
+        &self,
+        pool_address: Address,
+        tx_hash: TxHash,
+    ) -> Result<Vec<LocalizedTransactionTrace>> {
+        let pool_traces_file = self.pool_traces_file(pool_address, &tx_hash);
+
+        self.fetch_cached(tx_hash, &pool_traces_file, async || {
+            self.trace_pool_calls(pool_address, tx_hash).await
+        })
+        .await
+    }
+
+    /// Like `fetch_pool_traces` without caching the fetched traces, every cached pool trace is
+    /// decoded by a rebuild and an inspected transaction may be outside the scanned blocks
+    pub async fn read_pool_traces(
+        &self,
+        pool_address: Address,
+        tx_hash: TxHash,
+    ) -> Result<Vec<LocalizedTransactionTrace>> {
+        let pool_traces_file = self.pool_traces_file(pool_address, &tx_hash);
+        if pool_traces_file.exists() {
+            return read_json_gz(&pool_traces_file)
+                .wrap_err(format!("Failed to read {:?}", pool_traces_file));
+        }
+
+        self.trace_pool_calls(pool_address, tx_hash).await
+    }
+
+    /// Keep the pool traces returned by trace_filter, grouped by transaction
+    pub fn store_pool_traces(
+        &self,
+        pool_address: Address,
+        localized_traces: &[LocalizedTransactionTrace],
+    ) -> Result<()> {
+        let mut localized_traces_by_tx_hash = BTreeMap::<TxHash, Vec<_>>::new();
+        for localized_trace in localized_traces {
+            let Some(tx_hash) = localized_trace.transaction_hash else {
+                continue;
+            };
+            localized_traces_by_tx_hash
+                .entry(tx_hash)
+                .or_default()
+                .push(localized_trace);
+        }
+
+        for (tx_hash, localized_traces) in localized_traces_by_tx_hash {
+            write_json_gz(
+                &self.pool_traces_file(pool_address, &tx_hash),
+                &localized_traces,
+            )
+            .wrap_err(format!("Failed to cache the pool traces {}", tx_hash))?;
+        }
+
+        Ok(())
+    }
+
+    /// Every cached pool trace, ordered by block, transaction and trace address
+    pub fn load_pool_traces(
+        &self,
+        pool_address: Address,
+    ) -> Result<Vec<LocalizedTransactionTrace>> {
+        let pool_traces_dir = self.cache_dir.join(pool_address.to_string());
+        info!("Reading {:?}...", pool_traces_dir);
+
+        let mut localized_traces = Vec::new();
+        for entry in std::fs::read_dir(&pool_traces_dir)
+            .wrap_err(format!("No pool traces cached in {:?}", pool_traces_dir))?
+        {
+            let path = entry?.path();
+            if !path.to_string_lossy().ends_with(".traces.json.gz") {
+                continue;
+            }
+            localized_traces.extend(
+                read_json_gz::<Vec<LocalizedTransactionTrace>>(&path)
+                    .wrap_err(format!("Failed to read {:?}", path))?,
+            );
+        }
+        sort_localized_traces(&mut localized_traces);
+        info!(
+            "Reading {:?} done.({})",
+            pool_traces_dir,
+            localized_traces.len()
+        );
+
+        Ok(localized_traces)
+    }
+
+    /// Remove every cached file of the transactions from `from_block`, orphaned by a reorg, so
+    /// a rebuild cannot decode them again
+    pub fn rollback(&self, pool_address: Address, from_block: BlockNumber) -> Result<()> {
+        let pool_traces_dir = self.cache_dir.join(pool_address.to_string());
+        if !pool_traces_dir.exists() {
+            return Ok(());
+        }
+
+        let mut removed_count = 0;
+        for entry in std::fs::read_dir(&pool_traces_dir)
+            .wrap_err(format!("Failed to read {:?}", pool_traces_dir))?
+        {
+            let path = entry?.path();
+            let Some(tx_hash) = path
+                .file_name()
+                .and_then(|file_name| file_name.to_str())
+                .and_then(|file_name| file_name.strip_suffix(".traces.json.gz"))
+                .and_then(|tx_hash| tx_hash.parse::<TxHash>().ok())
+            else {
+                continue;
+            };
+            let localized_traces = read_json_gz::<Vec<LocalizedTransactionTrace>>(&path)
+                .wrap_err(format!("Failed to read {:?}", path))?;
+            if !localized_traces.iter().any(|localized_trace| {
+                localized_trace
+                    .block_number
+                    .is_some_and(|block_number| block_number >= from_block)
+            }) {
+                continue;
+            }
+
+            for file in [
+                path,
+                self.vm_trace_file(&tx_hash, TraceBackend::Parity),
+                self.vm_trace_file(&tx_hash, TraceBackend::Geth),
+                self.receipt_file(&tx_hash),
+                self.transaction_file(&tx_hash),
+            ] {
+                if file.exists() {
+                    std::fs::remove_file(&file).wrap_err(format!("Failed to remove {:?}", file))?;
+                }
+            }
+            removed_count += 1;
+        }
+        info!(
+            "Removed {} transactions from block {} from the trace cache",
+            removed_count, from_block
+        );
+
+        Ok(())
+    }
+
+    async fn trace_pool_calls(
+        &self,
+        pool_address: Address,
+        tx_hash: TxHash,
+    ) -> Result<Vec<LocalizedTransactionTrace>> {
+        Ok(self
+            .provider()?
+            .trace_transaction(tx_hash)
+            .await?
+            .into_iter()
+            .filter(|localized_trace| {
+                localized_trace
+                    .trace
+                    .action
+                    .as_call()
+                    .is_some_and(|call_action| call_action.to == pool_address)
+            })
+            .collect())
+    }
+
+    fn provider(&self) -> Result<&ProviderFiller> {
+        self.provider
+            .as_ref()
+            .ok_or_else(|| eyre!("Not in the trace cache {:?} (offline)", self.cache_dir))
+    }
+
+    fn vm_trace_file(&self, tx_hash: &TxHash, trace_backend: TraceBackend) -> PathBuf {
+        self.cache_dir
+            .join(format!("{tx_hash}.vm.{}.json.gz", trace_backend.name()))
+    }
+
+    fn receipt_file(&self, tx_hash: &TxHash) -> PathBuf {
+        self.cache_dir.join(format!("{tx_hash}.receipt.json.gz"))
+    }
+
+    fn transaction_file(&self, tx_hash: &TxHash) -> PathBuf {
+        self.cache_dir.join(format!("{tx_hash}.tx.json.gz"))
+    }
+
+    fn pool_traces_file(&self, pool_address: Address, tx_hash: &TxHash) -> PathBuf {
+        self.cache_dir
+            .join(pool_address.to_string())
+            .join(format!("{tx_hash}.traces.json.gz"))
+    }
+
+    /// Read `path` or fetch then cache it, one fetch at a time per transaction. The gzip and
+    /// JSON work runs on the blocking pool, vm traces weigh several MB.
+    async fn fetch_cached<T: Serialize + DeserializeOwned + Send + 'static>(
+        &self,
+        tx_hash: TxHash,
+        path: &Path,
+        fetch: impl AsyncFnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let fetch_lock = self
+            .fetch_lock_by_tx_hash
+            .lock()
+            .expect("Trace cache lock poisoned")
+            .entry(tx_hash)
+            .or_default()
+            .clone();
+        let fetch_guard = fetch_lock.lock().await;
+
+        let value = read_or_fetch(path, fetch).await;
+
+        drop(fetch_guard);
+        let mut fetch_lock_by_tx_hash = self
+            .fetch_lock_by_tx_hash
+            .lock()
+            .expect("Trace cache lock poisoned");
+        // Only the map and this fetch hold the lock, no other fetch of the transaction waits
+        if Arc::strong_count(&fetch_lock) == 2 {
+            fetch_lock_by_tx_hash.remove(&tx_hash);
+        }
+
+        value
+    }
+}
+
+async fn read_or_fetch<T: Serialize + DeserializeOwned + Send + 'static>(
+    path: &Path,
+    fetch: impl AsyncFnOnce() -> Result<T>,
+) -> Result<T> {
+    if path.exists() {
+        let cached_path = path.to_path_buf();
+        match tokio::task::spawn_blocking(move || read_json_gz(&cached_path)).await? {
+            Ok(value) => {
+                debug!("{:?} read from the cache", path);
+                return Ok(value);
+            }
+            Err(error) => warn!("Ignore the cached {:?}: {:?}", path, error),
+        }
+    }
+
+    let value = fetch()
+        .await
+        .wrap_err(format!("Failed to fetch {:?}", path))?;
+    let cached_path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || write_json_gz(&cached_path, &value).map(|()| value))
+        .await?
+        .wrap_err(format!("Failed to cache {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_vm_trace_cached_per_backend() -> Result<()> {
+        let cache_dir = std::env::temp_dir().join(format!("trace-cache-{}", std::process::id()));
+        let tx_hash = TxHash::repeat_byte(0xaa);
+        let parity_trace_cache =
+            TraceCache::try_new(None, TraceBackend::Parity, cache_dir.clone())?;
+        write_json_gz(
+            &parity_trace_cache.vm_trace_file(&tx_hash, TraceBackend::Parity),
+            &VmTrace {
+                code: Default::default(),
+                ops: Vec::new(),
+            },
+        )?;
+        assert!(parity_trace_cache.fetch_vm_trace(tx_hash).await.is_ok());
+
+        // Offline, the parity trace is never served to the geth backend
+        let geth_trace_cache = TraceCache::try_new(None, TraceBackend::Geth, cache_dir.clone())?;
+        assert!(geth_trace_cache.fetch_vm_trace(tx_hash).await.is_err());
+
+        std::fs::remove_dir_all(&cache_dir)?;
+        Ok(())
+    }
+}
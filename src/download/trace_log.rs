@@ -0,0 +1,170 @@
+use crate::helper::{open_csv, read_csv, rewrite_csv};
+use alloy::primitives::BlockNumber;
+use eyre::Result;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// A csv row about a single pool trace
+pub trait TraceRow {
+    fn block_number(&self) -> BlockNumber;
+    fn tx_hash(&self) -> &str;
+    fn trace_path(&self) -> &str;
+}
+
+/// Append only csv of pool trace rows, at most one row per trace
+pub struct TraceLog<T> {
+    csv_writer: csv::Writer<std::fs::File>,
+    csv_file: PathBuf,
+    tx_hash_trace_paths: HashSet<(String, String)>,
+    rows: std::marker::PhantomData<T>,
+}
+
+impl<T: TraceRow + Serialize + DeserializeOwned> TraceLog<T> {
+    pub fn try_new(csv_file: PathBuf) -> Result<Self> {
+        let (rows, csv_writer) = open_csv::<T>(&csv_file)?;
+
+        Ok(Self {
+            csv_writer,
+            csv_file,
+            tx_hash_trace_paths: index_rows(&rows),
+            rows: std::marker::PhantomData,
+        })
+    }
+
+    pub fn contains(&self, tx_hash: &str, trace_path: &str) -> bool {
+        self.tx_hash_trace_paths
+            .contains(&(tx_hash.to_string(), trace_path.to_string()))
+    }
+
+    /// Append the row, unless its trace already has one
+    pub fn insert(&mut self, row: T) -> Result<()> {
+        let is_new = self
+            .tx_hash_trace_paths
+            .insert((row.tx_hash().to_string(), row.trace_path().to_string()));
+        if is_new {
+            self.csv_writer.serialize(&row)?;
+        }
+
+        Ok(())
+    }
+
+    /// Forget the rows of these traces, the csv is rewritten without them when it has any
+    pub fn remove(&mut self, tx_hash_trace_paths: &HashSet<(String, String)>) -> Result<()> {
+        if self.tx_hash_trace_paths.is_disjoint(tx_hash_trace_paths) {
+            return Ok(());
+        }
+        self.csv_writer.flush()?;
+
+        let rows = read_csv::<T>(&self.csv_file)?
+            .map(|(_, rows)| rows)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|row| {
+                !tx_hash_trace_paths
+                    .contains(&(row.tx_hash().to_string(), row.trace_path().to_string()))
+            })
+            .collect();
+        let (rows, csv_writer) = rewrite_csv(&self.csv_file, rows)?;
+
+        self.csv_writer = csv_writer;
+        self.tx_hash_trace_paths = index_rows(&rows);
+
+        Ok(())
+    }
+
+    /// Forget every row from `from_block`, the csv is rewritten without them
+    pub fn rollback(&mut self, from_block: BlockNumber) -> Result<()> {
+        self.csv_writer.flush()?;
+
+        let rows = read_csv::<T>(&self.csv_file)?
+            .map(|(_, rows)| rows)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|row| row.block_number() < from_block)
+            .collect();
+        let (rows, csv_writer) = rewrite_csv(&self.csv_file, rows)?;
+
+        self.csv_writer = csv_writer;
+        self.tx_hash_trace_paths = index_rows(&rows);
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Rows of `csv_file` whose trace has no row in `rebuilt_csv_file`
+pub fn missing_rows<T: TraceRow + DeserializeOwned>(
+    csv_file: &Path,
+    rebuilt_csv_file: &Path,
+) -> Result<Vec<T>> {
+    let Some((_, rows)) = read_csv::<T>(csv_file)? else {
+        return Ok(Vec::new());
+    };
+    let rebuilt_tx_hash_trace_paths = read_csv::<T>(rebuilt_csv_file)?
+        .map(|(_, rebuilt_rows)| index_rows(&rebuilt_rows))
+        .unwrap_or_default();
+
+    Ok(rows
+        .into_iter()
+        .filter(|row| {
+            !rebuilt_tx_hash_trace_paths
+                .contains(&(row.tx_hash().to_string(), row.trace_path().to_string()))
+        })
+        .collect())
+}
+
+fn index_rows<T: TraceRow>(rows: &[T]) -> HashSet<(String, String)> {
+    rows.iter()
+        .map(|row| (row.tx_hash().to_string(), row.trace_path().to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::skipped::{SkipReason, SkippedCsv};
+
+    fn skipped_csv(block_number: u64, tx_hash: &str) -> SkippedCsv {
+        SkippedCsv {
+            block_number,
+            tx_hash: tx_hash.to_string(),
+            trace_path: "0,1".to_string(),
+            reason: SkipReason::UnknownCall,
+        }
+    }
+
+    #[test]
+    fn test_insert_once_and_rollback() -> Result<()> {
+        let csv_file = std::env::temp_dir().join(format!("trace-log-{}.csv", std::process::id()));
+
+        let mut trace_log = TraceLog::try_new(csv_file.clone())?;
+        trace_log.insert(skipped_csv(10, "0xaa"))?;
+        trace_log.insert(skipped_csv(10, "0xaa"))?;
+        trace_log.insert(skipped_csv(12, "0xbb"))?;
+        trace_log.flush()?;
+        assert_eq!(read_csv::<SkippedCsv>(&csv_file)?.unwrap().1.len(), 2);
+
+        trace_log.rollback(11)?;
+        assert!(trace_log.contains("0xaa", "0,1"));
+        assert!(!trace_log.contains("0xbb", "0,1"));
+
+        let mut trace_log = TraceLog::<SkippedCsv>::try_new(csv_file.clone())?;
+        assert!(trace_log.contains("0xaa", "0,1"));
+        assert!(!trace_log.contains("0xbb", "0,1"));
+
+        trace_log.insert(skipped_csv(12, "0xbb"))?;
+        trace_log.remove(&HashSet::from([("0xaa".to_string(), "0,1".to_string())]))?;
+        assert!(!trace_log.contains("0xaa", "0,1"));
+        assert!(trace_log.contains("0xbb", "0,1"));
+        assert_eq!(read_csv::<SkippedCsv>(&csv_file)?.unwrap().1.len(), 1);
+
+        std::fs::remove_file(&csv_file)?;
+        Ok(())
+    }
+}
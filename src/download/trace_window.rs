@@ -0,0 +1,199 @@
+use alloy::primitives::BlockNumber;
+use eyre::{Result, eyre};
+use log::{info, warn};
+use std::time::Duration;
+
+const INITIAL_WINDOW: u64 = 5_000;
+const MIN_WINDOW: u64 = 1;
+const MAX_WINDOW: u64 = 100_000;
+/// Below this amount of results in every range of a batch, the window is doubled
+const SMALL_RESULT: usize = 500;
+const RANGE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Size of the discovery block ranges (trace_filter, eth_getLogs), halved when the node refuses
+/// a range and doubled when the ranges are almost empty
+pub struct TraceWindow {
+    size: u64,
+}
+
+impl Default for TraceWindow {
+    fn default() -> Self {
+        TraceWindow {
+            size: INITIAL_WINDOW,
+        }
+    }
+}
+
+impl TraceWindow {
+    /// Split the next `count` ranges starting at `from_block`
+    pub fn ranges(
+        &self,
+        from_block: BlockNumber,
+        end_block: BlockNumber,
+        count: usize,
+    ) -> Vec<(BlockNumber, BlockNumber)> {
+        (from_block..=end_block)
+            .step_by(self.size as usize)
+            .take(count)
+            .map(|from_block| {
+                let to_block = from_block.saturating_add(self.size - 1).min(end_block);
+                (from_block, to_block)
+            })
+            .collect()
+    }
+
+    /// Resize the window from the outcome of the last batch of ranges
+    pub fn adapt(&mut self, has_split: bool, max_results: usize) {
+        let size = if has_split {
+            (self.size / 2).max(MIN_WINDOW)
+        } else if max_results < SMALL_RESULT {
+            self.size.saturating_mul(2).min(MAX_WINDOW)
+        } else {
+            self.size
+        };
+
+        if size != self.size {
+            info!(
+                "Discovery window {} -> {} blocks (split: {}, max results: {})",
+                self.size, size, has_split, max_results
+            );
+            self.size = size;
+        }
+    }
+}
+
+/// Fetch a block range with `fetch`, recursively halving the range when the node answer is too
+/// large or times out. Also return if the range had to be split.
+pub async fn fetch_adaptive<T, F, Fut>(
+    method: &str,
+    from_block: BlockNumber,
+    to_block: BlockNumber,
+    fetch: &F,
+) -> Result<(Vec<T>, bool)>
+where
+    F: Fn(BlockNumber, BlockNumber) -> Fut,
+    Fut: Future<Output = Result<Vec<T>>>,
+{
+    let error = match tokio::time::timeout(RANGE_TIMEOUT, fetch(from_block, to_block)).await {
+        Ok(Ok(values)) => return Ok((values, false)),
+        Ok(Err(error)) => error,
+        Err(_) => eyre!("{} from {} to {} timed out", method, from_block, to_block),
+    };
+
+    if from_block >= to_block || !is_response_too_large_or_timeout(&error) {
+        return Err(error);
+    }
+
+    let middle_block = from_block + (to_block - from_block) / 2;
+    warn!(
+        "Splitting {} range {}..={} in two: {}",
+        method,
+        from_block,
+        to_block,
+        error.root_cause()
+    );
+
+    let (mut values, _) = Box::pin(fetch_adaptive(method, from_block, middle_block, fetch)).await?;
+    let (second_half, _) =
+        Box::pin(fetch_adaptive(method, middle_block + 1, to_block, fetch)).await?;
+    values.extend(second_half);
+
+    Ok((values, true))
+}
+
+fn is_response_too_large_or_timeout(error: &eyre::Report) -> bool {
+    const PATTERNS: [&str; 9] = [
+        "response size",
+        "too large",
+        "too big",
+        "query returned more than",
+        "too many results",
+        "block range too",
+        "max block range",
+        "timeout",
+        "timed out",
+    ];
+    // A rate limit is handled by the retry backoff, splitting the range would only add requests
+    const RATE_LIMIT_PATTERNS: [&str; 3] = ["rate limit", "too many requests", "request limit"];
+
+    error.chain().any(|cause| {
+        let cause = cause.to_string().to_lowercase();
+        PATTERNS.iter().any(|pattern| cause.contains(pattern))
+            && !RATE_LIMIT_PATTERNS
+                .iter()
+                .any(|pattern| cause.contains(pattern))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_response_too_large_or_timeout() {
+        for message in [
+            "query returned more than 10000 results",
+            "Response size exceeded the 150MB limit",
+            "trace_filter response too large",
+            "too many results, try a smaller block range",
+            "block range too wide",
+            "request timed out",
+        ] {
+            assert!(
+                is_response_too_large_or_timeout(&eyre!(message.to_string())),
+                "{message}"
+            );
+        }
+
+        for message in [
+            "rate limit exceeded",
+            "request limit reached, retry later",
+            "429 Too Many Requests",
+            "execution reverted",
+            "header not found",
+        ] {
+            assert!(
+                !is_response_too_large_or_timeout(&eyre!(message.to_string())),
+                "{message}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_ranges() {
+        let trace_window = TraceWindow { size: 10 };
+
+        assert_eq!(
+            trace_window.ranges(100, 125, 5),
+            vec![(100, 109), (110, 119), (120, 125)]
+        );
+        assert_eq!(
+            trace_window.ranges(100, 125, 2),
+            vec![(100, 109), (110, 119)]
+        );
+        assert_eq!(trace_window.ranges(100, 100, 5), vec![(100, 100)]);
+        assert!(trace_window.ranges(101, 100, 5).is_empty());
+    }
+
+    #[test]
+    fn test_adapt() {
+        let mut trace_window = TraceWindow::default();
+
+        trace_window.adapt(true, SMALL_RESULT);
+        assert_eq!(trace_window.size, INITIAL_WINDOW / 2);
+
+        trace_window.adapt(false, SMALL_RESULT);
+        assert_eq!(trace_window.size, INITIAL_WINDOW / 2);
+
+        trace_window.adapt(false, SMALL_RESULT - 1);
+        assert_eq!(trace_window.size, INITIAL_WINDOW);
+
+        let mut trace_window = TraceWindow { size: MIN_WINDOW };
+        trace_window.adapt(true, 0);
+        assert_eq!(trace_window.size, MIN_WINDOW);
+
+        let mut trace_window = TraceWindow { size: MAX_WINDOW };
+        trace_window.adapt(false, 0);
+        assert_eq!(trace_window.size, MAX_WINDOW);
+    }
+}
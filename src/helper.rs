@@ -3,8 +3,17 @@ use alloy::primitives::{B256, Bytes, TxHash, U256, b256};
 use alloy::providers::ext::TraceApi;
 use alloy::rpc::types::trace::parity::{VmInstruction, VmTrace};
 use alloy::sol_types::private::u256;
-use eyre::{OptionExt, Result};
+use eyre::{OptionExt, Result, WrapErr};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use log::info;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
 
 pub trait DivUp
 where
@@ -67,6 +76,115 @@ impl StringifyArrayUsize for &[usize] {
     }
 }
 
+/// Parse an RFC3339 date or a YYYY-MM-DD date (at 00:00:00 UTC) into a unix timestamp
+pub fn parse_timestamp(date: &str) -> Result<u64> {
+    let date_time = match chrono::DateTime::parse_from_rfc3339(date) {
+        Ok(date_time) => date_time.to_utc(),
+        Err(_) => chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .wrap_err(format!("Failed to parse date {:?}", date))?
+            .and_time(chrono::NaiveTime::MIN)
+            .and_utc(),
+    };
+
+    u64::try_from(date_time.timestamp()).wrap_err(format!("Date {:?} is before 1970", date))
+}
+
+/// Read every row of a csv file, missing columns get their serde default
+pub fn read_csv<T: DeserializeOwned>(path: &Path) -> Result<Option<(csv::StringRecord, Vec<T>)>> {
+    let Ok(mut csv_reader) = csv::Reader::from_path(path) else {
+        return Ok(None);
+    };
+
+    let header = csv_reader.headers()?.clone();
+    let rows = csv_reader
+        .deserialize::<T>()
+        .collect::<std::result::Result<Vec<T>, _>>()
+        .wrap_err(format!("Failed to read {:?}", path))?;
+
+    Ok(Some((header, rows)))
+}
+
+/// Read every row of a csv file and open it in append mode. The file is created when missing and
+/// rewritten when its header is outdated.
+pub fn open_csv<T: DeserializeOwned + Serialize>(
+    path: &Path,
+) -> Result<(Vec<T>, csv::Writer<File>)> {
+    info!("Reading {:?}...", path);
+    let Some((header, rows)) = read_csv::<T>(path)? else {
+        info!("No {:?} file found", path);
+        return Ok((Vec::new(), csv::Writer::from_path(path)?));
+    };
+    info!("Reading {:?} done.({})", path, rows.len());
+
+    let is_header_outdated = match rows.first() {
+        Some(row) => csv_header(row)? != header,
+        None => true,
+    };
+    if is_header_outdated {
+        info!("Rewriting {:?} with the current columns", path);
+        return rewrite_csv(path, rows);
+    }
+
+    let csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(OpenOptions::new().append(true).open(path)?);
+
+    Ok((rows, csv_writer))
+}
+
+/// Replace a csv file by `rows` (through a temporary file) and open it in append mode
+pub fn rewrite_csv<T: Serialize>(path: &Path, rows: Vec<T>) -> Result<(Vec<T>, csv::Writer<File>)> {
+    let mut tmp_path = path.to_path_buf();
+    tmp_path.set_extension("csv.tmp");
+
+    let mut csv_writer = csv::Writer::from_path(&tmp_path)?;
+    for row in rows.iter() {
+        csv_writer.serialize(row)?;
+    }
+    csv_writer.flush()?;
+    std::fs::rename(&tmp_path, path).wrap_err(format!("Failed to replace {:?}", path))?;
+
+    if rows.is_empty() {
+        return Ok((rows, csv::Writer::from_path(path)?));
+    }
+    let csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(OpenOptions::new().append(true).open(path)?);
+
+    Ok((rows, csv_writer))
+}
+
+fn csv_header<T: Serialize>(row: &T) -> Result<csv::StringRecord> {
+    let mut csv_writer = csv::Writer::from_writer(Vec::new());
+    csv_writer.serialize(row)?;
+    let data = csv_writer.into_inner()?;
+
+    Ok(csv::Reader::from_reader(data.as_slice()).headers()?.clone())
+}
+
+pub fn read_json_gz<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let decoder = GzDecoder::new(BufReader::new(File::open(path)?));
+
+    Ok(serde_json::from_reader(decoder)?)
+}
+
+/// Write to a temporary file first, an interrupted run never leaves a truncated file
+pub fn write_json_gz<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("gz.tmp");
+    let mut encoder = GzEncoder::new(
+        BufWriter::new(File::create(&tmp_path)?),
+        Compression::default(),
+    );
+    serde_json::to_writer(&mut encoder, value)?;
+    encoder.finish()?.flush()?;
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
 pub async fn fetch_sub_vm_trace(
     provider: &ProviderFiller,
     tx_hash: TxHash,
@@ -152,16 +270,39 @@ impl StateBySubPath {
         state_by_sub_path.find_storage_value(vm_trace, &[]);
         state_by_sub_path
     }
+    /// `{load_map: {key: {"[sub, path]": [values]}}, store_map: ...}`, JSON keys can't be arrays
+    pub fn to_json(&self) -> serde_json::Value {
+        let map_to_json = |map: &HashMap<B256, BTreeMap<Vec<usize>, Vec<B256>>>| {
+            map.iter()
+                .map(|(key, value_by_sub_path)| {
+                    (
+                        key.to_string(),
+                        value_by_sub_path
+                            .iter()
+                            .map(|(sub_path, values)| {
+                                (format!("{:?}", sub_path), serde_json::json!(values))
+                            })
+                            .collect::<serde_json::Map<_, _>>()
+                            .into(),
+                    )
+                })
+                .collect::<serde_json::Map<_, _>>()
+        };
+
+        serde_json::json!({
+            "load_map": map_to_json(&self.load_map),
+            "store_map": map_to_json(&self.store_map),
+        })
+    }
     pub fn find_storage_value(&mut self, vm_trace: &VmTrace, sub_path: &[usize]) {
         let mut sub_path_counter = 0;
 
         for (instruction_position, instruction) in vm_trace.ops.iter().enumerate() {
-            if let Some(next_instruction) = vm_trace.ops.get(instruction_position + 1) {
-                if let Some((load_key, load_value)) =
+            if let Some(next_instruction) = vm_trace.ops.get(instruction_position + 1)
+                && let Some((load_key, load_value)) =
                     Self::extract_storage_load(instruction, next_instruction, &vm_trace.code)
-                {
-                    Self::upsert_in_map(&mut self.load_map, &load_key, &load_value, sub_path);
-                }
+            {
+                Self::upsert_in_map(&mut self.load_map, &load_key, &load_value, sub_path);
             }
             if let Some((store_key, store_value)) = Self::extract_storage_store(instruction) {
                 Self::upsert_in_map(&mut self.store_map, &store_key, &store_value, sub_path);
@@ -265,28 +406,22 @@ impl StateBySubPath {
     }
 }
 
-pub fn save_trace_to_file(
-    mut vm_trace: VmTrace,
-    tx_hash: &TxHash,
-    prepend_name: &str,
-) -> Result<()> {
+/// Pretty JSON of the vm trace, every instruction annotated with its opcode and sub path
+pub fn annotated_trace_json(mut vm_trace: VmTrace) -> Result<String> {
     add_opcode_to_instruction(&mut vm_trace, &[]);
-    std::fs::write(
-        format!("{prepend_name}-{tx_hash}.json"),
-        serde_json::to_string_pretty(&vm_trace)?,
-    )?;
-
-    Ok(())
+    Ok(serde_json::to_string_pretty(&vm_trace)?)
 }
 
 fn add_opcode_to_instruction(vm_trace: &mut VmTrace, sub_path: &[usize]) {
     let mut sub_path_counter = 0;
 
     for instruction in vm_trace.ops.iter_mut() {
-        instruction.op = vm_trace
-            .code
-            .get(instruction.pc)
-            .map(|op| format!("{:#04x}", op));
+        if instruction.op.is_none() {
+            instruction.op = vm_trace
+                .code
+                .get(instruction.pc)
+                .map(|op| format!("{:#04x}", op));
+        }
 
         instruction.idx = Some(format!("{:?}", sub_path));
 
@@ -1,21 +1,40 @@
 mod download;
 pub mod helper;
+mod paths;
 mod process;
+mod report;
 
-use clap::Parser;
+use crate::download::{DownloadArgs, InspectArgs, RebuildArgs, RetryArgs};
+use crate::paths::DataPaths;
+use clap::{Parser, Subcommand};
 use eyre::Result;
 
 /// Generate sDAI<>EURe incident report
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// GnosisChain RPC url: If you want to download on-chain data
-    #[arg(short, long)]
-    rpc_url: Option<String>,
+    #[command(subcommand)]
+    command: Command,
 
-    /// The starting block for downloading
-    #[arg(short, long, default_value = "30274134")]
-    start_block_download: u64,
+    #[command(flatten)]
+    data_paths: DataPaths,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download on-chain data (blocks.csv, swaps.csv, liquidity.csv)
+    Download(DownloadArgs),
+    /// Generate processed data from downloaded data (sma-eur-usdt.csv)
+    Process,
+    /// Generate the incident report from processed data
+    Report,
+    /// Decode every pool call of a single transaction without writing to swaps.csv
+    Inspect(InspectArgs),
+    /// Regenerate swaps.csv, liquidity.csv, skipped.csv and failures.csv from the trace cache and
+    /// blocks.csv, without any rpc call. Refuse to delete a row the cache cannot rebuild.
+    Rebuild(RebuildArgs),
+    /// Decode again the traces quarantined in failures.csv, without any rpc call
+    Retry(RetryArgs),
 }
 
 #[tokio::main]
@@ -23,10 +42,14 @@ async fn main() -> Result<()> {
     env_logger::init();
 
     let args = Args::parse();
-    if let Some(rpc_url) = args.rpc_url {
-        download::start(&rpc_url, args.start_block_download).await?;
-    }
-    process::start()?;
+    args.data_paths.create_dirs()?;
 
-    Ok(())
+    match args.command {
+        Command::Download(download_args) => download::start(&download_args, &args.data_paths).await,
+        Command::Process => process::start(&args.data_paths),
+        Command::Report => report::start(),
+        Command::Inspect(inspect_args) => download::inspect(&inspect_args, &args.data_paths).await,
+        Command::Rebuild(rebuild_args) => download::rebuild(&rebuild_args, &args.data_paths).await,
+        Command::Retry(retry_args) => download::retry(&retry_args, &args.data_paths).await,
+    }
 }
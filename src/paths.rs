@@ -0,0 +1,158 @@
+use eyre::Result;
+use std::path::PathBuf;
+
+/// Location of every file read or written, relative to `--data-dir` unless overridden
+#[derive(clap::Args, Debug, Clone)]
+pub struct DataPaths {
+    /// Directory holding the downloaded and processed data
+    #[arg(long, global = true, default_value = "data")]
+    pub data_dir: PathBuf,
+
+    /// Override <DATA_DIR>/blocks.csv
+    #[arg(long, global = true)]
+    pub blocks_csv: Option<PathBuf>,
+
+    /// Override <DATA_DIR>/swaps.csv
+    #[arg(long, global = true)]
+    pub swaps_csv: Option<PathBuf>,
+
+    /// Override <DATA_DIR>/liquidity.csv, the ledger of every join and exit
+    #[arg(long, global = true)]
+    pub liquidity_csv: Option<PathBuf>,
+
+    /// Override <DATA_DIR>/skipped.csv, the pool interactions left out of swaps.csv
+    #[arg(long, global = true)]
+    pub skipped_csv: Option<PathBuf>,
+
+    /// Override <DATA_DIR>/failures.csv, the pool traces quarantined by `download --quarantine`
+    #[arg(long, global = true)]
+    pub failures_csv: Option<PathBuf>,
+
+    /// Override <DATA_DIR>/sma-eur-usdt.csv
+    #[arg(long, global = true)]
+    pub sma_csv: Option<PathBuf>,
+
+    /// Override <DATA_DIR>/binance-eur-usdt-klines/
+    #[arg(long, global = true)]
+    pub klines_dir: Option<PathBuf>,
+
+    /// Override <DATA_DIR>/traces/, where a failure bundle is written per transaction failing to
+    /// decode
+    #[arg(long, global = true)]
+    pub traces_dir: Option<PathBuf>,
+
+    /// Override <DATA_DIR>/trace-cache/, where the replayed vm traces are kept between runs
+    #[arg(long, global = true)]
+    pub trace_cache_dir: Option<PathBuf>,
+
+    /// Override <DATA_DIR>/checkpoint.json
+    #[arg(long, global = true)]
+    pub checkpoint: Option<PathBuf>,
+}
+
+impl DataPaths {
+    pub fn blocks_csv(&self) -> PathBuf {
+        self.resolve(&self.blocks_csv, "blocks.csv")
+    }
+
+    pub fn swaps_csv(&self) -> PathBuf {
+        self.resolve(&self.swaps_csv, "swaps.csv")
+    }
+
+    pub fn liquidity_csv(&self) -> PathBuf {
+        self.resolve(&self.liquidity_csv, "liquidity.csv")
+    }
+
+    pub fn skipped_csv(&self) -> PathBuf {
+        self.resolve(&self.skipped_csv, "skipped.csv")
+    }
+
+    pub fn failures_csv(&self) -> PathBuf {
+        self.resolve(&self.failures_csv, "failures.csv")
+    }
+
+    pub fn sma_csv(&self) -> PathBuf {
+        self.resolve(&self.sma_csv, "sma-eur-usdt.csv")
+    }
+
+    pub fn klines_dir(&self) -> PathBuf {
+        self.resolve(&self.klines_dir, "binance-eur-usdt-klines")
+    }
+
+    pub fn traces_dir(&self) -> PathBuf {
+        self.resolve(&self.traces_dir, "traces")
+    }
+
+    pub fn trace_cache_dir(&self) -> PathBuf {
+        self.resolve(&self.trace_cache_dir, "trace-cache")
+    }
+
+    pub fn checkpoint(&self) -> PathBuf {
+        self.resolve(&self.checkpoint, "checkpoint.json")
+    }
+
+    /// Create the data dir and the parent dir of every overridden path, so an override may point
+    /// to a directory not created yet
+    pub fn create_dirs(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.data_dir)?;
+        let path_overrides = [
+            &self.blocks_csv,
+            &self.swaps_csv,
+            &self.liquidity_csv,
+            &self.skipped_csv,
+            &self.failures_csv,
+            &self.sma_csv,
+            &self.klines_dir,
+            &self.traces_dir,
+            &self.trace_cache_dir,
+            &self.checkpoint,
+        ];
+        for path in path_overrides.into_iter().flatten() {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve(&self, path_override: &Option<PathBuf>, file_name: &str) -> PathBuf {
+        path_override
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join(file_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct TestArgs {
+        #[command(flatten)]
+        data_paths: DataPaths,
+    }
+
+    #[test]
+    fn test_create_dirs() -> Result<()> {
+        let temp_dir = std::env::temp_dir().join(format!("paths-{}", std::process::id()));
+        let data_dir = temp_dir.join("data");
+        let swaps_csv = temp_dir.join("out/swaps/swaps.csv");
+        let data_paths = TestArgs::parse_from([
+            "test",
+            "--data-dir",
+            data_dir.to_str().unwrap(),
+            "--swaps-csv",
+            swaps_csv.to_str().unwrap(),
+        ])
+        .data_paths;
+
+        data_paths.create_dirs()?;
+        assert!(data_dir.is_dir());
+        assert!(swaps_csv.parent().unwrap().is_dir());
+        assert_eq!(data_paths.swaps_csv(), swaps_csv);
+
+        std::fs::remove_dir_all(&temp_dir)?;
+        Ok(())
+    }
+}
@@ -1,8 +1,9 @@
+use crate::paths::DataPaths;
 use crate::process::sma_eur_usdt::generate_sma_eur_usdt_csv;
 use eyre::Result;
 
 mod sma_eur_usdt;
 
-pub fn start() -> Result<()> {
-    generate_sma_eur_usdt_csv()
+pub fn start(data_paths: &DataPaths) -> Result<()> {
+    generate_sma_eur_usdt_csv(data_paths)
 }
@@ -1,10 +1,11 @@
+use crate::paths::DataPaths;
 use alloy::primitives::U256;
 use alloy::sol_types::private::u256;
 use eyre::{OptionExt, Result};
 use log::{debug, info};
+use std::path::Path;
 
 const SMA_LENGTH: usize = 10;
-const SMA_CSV_FILE: &str = "data/sma-eur-usdt.csv";
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 struct Kline {
@@ -31,15 +32,14 @@ impl Kline {
             .ok_or_eyre("Failed to put price to base 18")
     }
 
-    fn load() -> Result<Vec<Kline>> {
+    fn load(klines_dir: &Path) -> Result<Vec<Kline>> {
         let mut klines = Vec::new();
         info!("Loading klines");
         for year in 2023..=2025 {
             for month in 1..=12 {
-                let Ok(mut csv_reader) = csv::Reader::from_path(format!(
-                    "data/binance-eur-usdt-klines/EURUSDT-1m-{year}-{:02}.csv",
-                    month
-                )) else {
+                let Ok(mut csv_reader) = csv::Reader::from_path(
+                    klines_dir.join(format!("EURUSDT-1m-{year}-{:02}.csv", month)),
+                ) else {
                     debug!("Skip loading klines for year {} month {}", year, month);
                     continue;
                 };
@@ -68,13 +68,13 @@ struct SmaEurUsdtCsv {
     sma_price: String,
 }
 
-pub fn generate_sma_eur_usdt_csv() -> Result<()> {
+pub fn generate_sma_eur_usdt_csv(data_paths: &DataPaths) -> Result<()> {
     info!("Generating sma-eur-usdt.csv");
 
-    let mut csv_writer = csv::Writer::from_path(SMA_CSV_FILE)?;
+    let mut csv_writer = csv::Writer::from_path(data_paths.sma_csv())?;
 
-    let mut klines = Kline::load()?;
-    klines.sort_by(|a, b| a.open_timestamp.cmp(&b.open_timestamp));
+    let mut klines = Kline::load(&data_paths.klines_dir())?;
+    klines.sort_by_key(|k| k.open_timestamp);
 
     for (id, kline) in klines.iter().enumerate() {
         let window = klines
@@ -0,0 +1,8 @@
+use eyre::{Result, eyre};
+use log::info;
+
+pub fn start() -> Result<()> {
+    info!("Generating report...");
+
+    Err(eyre!("Report not implemented yet"))
+}